@@ -0,0 +1,329 @@
+//! An assembler for the spec's mnemonics, producing images `build::read_binary` can load.
+//!
+//! Each line holds optional `label:`s followed by an instruction or a directive, with `;`
+//! starting a comment. Operands are registers (`r0`..`r7`), numbers (decimal or `0x` hex),
+//! character literals like `'a'` or `'\n'`, and labels. The directives are:
+//!
+//! - `.data <word>, ...` for raw words
+//! - `.string "text"` for one word per character
+//! - `.pstring "text"` for a Synacor string: the length, then the characters
+
+use super::opcode::Opcode;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+// Addresses are 15 bits, so an image can't be any longer.
+const MEMORY_SIZE: usize = 32768;
+
+pub struct Error {
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+enum Word {
+    Value(u16),
+    Label(String),
+}
+
+/// Words waiting for labels to be resolved, along with the line they came from.
+struct Pending {
+    line: usize,
+    words: Vec<Word>,
+}
+
+/// Splits off the comment, leaving `;` alone inside quotes.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, ';') => return &line[..i],
+            _ => {}
+        }
+    }
+
+    line
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Splits operands on commas and whitespace, keeping quoted text together.
+fn tokens(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c == ',' || c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let mut token = String::new();
+        if c == '"' || c == '\'' {
+            token.push(chars.next().unwrap());
+            loop {
+                match chars.next() {
+                    Some('\\') => {
+                        token.push('\\');
+                        token.extend(chars.next());
+                    }
+                    Some(q) if q == c => {
+                        token.push(q);
+                        break;
+                    }
+                    Some(other) => token.push(other),
+                    None => return Err(format!("unterminated literal {}", token)),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ',' || c.is_whitespace() {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+        }
+
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+/// The characters of a quoted literal, with escapes as written by `{:?}`.
+fn unquote(token: &str, quote: char) -> Result<Vec<u16>, String> {
+    let inner = token
+        .strip_prefix(quote)
+        .and_then(|t| t.strip_suffix(quote))
+        .filter(|_| token.len() >= 2)
+        .ok_or_else(|| format!("expected a quoted literal, found {}", token))?;
+
+    let mut words = Vec::new();
+    let mut chars = inner.chars();
+
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some('u') => {
+                    let hex: String = chars.by_ref().skip(1).take_while(|c| *c != '}').collect();
+                    let code = u32::from_str_radix(&hex, 16).map_err(|_| format!("bad escape in {}", token))?;
+                    char::from_u32(code).ok_or_else(|| format!("bad escape in {}", token))?
+                }
+                Some(c @ ('\\' | '\'' | '"')) => c,
+                _ => return Err(format!("bad escape in {}", token)),
+            },
+            c => c,
+        };
+
+        words.push(u16::try_from(c as u32).map_err(|_| format!("character {:?} doesn't fit in a word", c))?);
+    }
+
+    Ok(words)
+}
+
+fn word(token: &str) -> Result<Word, String> {
+    if let Some(register) = token.strip_prefix('r').and_then(|r| r.parse::<u16>().ok()) {
+        return match register {
+            0..=7 => Ok(Word::Value(32768 + register)),
+            _ => Err(format!("no such register {}", token)),
+        };
+    }
+
+    if token.starts_with('\'') {
+        return match unquote(token, '\'')?.as_slice() {
+            [c] => Ok(Word::Value(*c)),
+            _ => Err(format!("character literal {} should hold one character", token)),
+        };
+    }
+
+    if token.starts_with(|c: char| c.is_ascii_digit()) {
+        let value = match token.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => token.parse(),
+        };
+        return value.map(Word::Value).map_err(|_| format!("invalid number {}", token));
+    }
+
+    if is_identifier(token) {
+        Ok(Word::Label(token.to_owned()))
+    } else {
+        Err(format!("unexpected {}", token))
+    }
+}
+
+/// Parses one statement (the line without its labels and comment) into words.
+fn statement(text: &str) -> Result<Vec<Word>, String> {
+    let tokens = tokens(text)?;
+    let (name, operands) = match tokens.split_first() {
+        Some(split) => split,
+        None => return Ok(Vec::new()),
+    };
+
+    match name.as_str() {
+        ".data" => operands.iter().map(|t| word(t)).collect(),
+        ".string" | ".pstring" => {
+            let text = match operands {
+                [text] => unquote(text, '"')?,
+                _ => return Err(format!("{} takes a single string", name)),
+            };
+
+            let length = (name == ".pstring").then_some(text.len() as u16);
+            Ok(length.into_iter().chain(text).map(Word::Value).collect())
+        }
+        _ => {
+            let opcode = Opcode::from_mnemonic(name).ok_or_else(|| format!("unknown instruction {}", name))?;
+
+            if operands.len() != opcode.arg_count() {
+                return Err(format!("{} takes {} operands, found {}", name, opcode.arg_count(), operands.len()));
+            }
+
+            let mut words = vec![Word::Value(opcode.code().unwrap())];
+            for operand in operands {
+                match word(operand)? {
+                    Word::Value(v) if v > 32775 => return Err(format!("operand {} out of range", operand)),
+                    w => words.push(w),
+                }
+            }
+            Ok(words)
+        }
+    }
+}
+
+/// Assembles `source`, or returns every error found in it.
+pub fn assemble(source: &str) -> Result<Vec<u16>, Vec<Error>> {
+    let mut labels = HashMap::new();
+    let mut pending = Vec::new();
+    let mut errors = Vec::new();
+    let mut address = 0;
+
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let mut text = strip_comment(line).trim();
+
+        // Any number of `label:`s may come before the statement
+        while let Some((label, rest)) = text.split_once(':').filter(|(l, _)| is_identifier(l.trim())) {
+            if address >= MEMORY_SIZE {
+                errors.push(Error {
+                    line: line_number,
+                    message: format!("label {} is at {}, past the end of memory", label.trim(), address),
+                });
+            }
+            if labels.insert(label.trim().to_owned(), address).is_some() {
+                errors.push(Error {
+                    line: line_number,
+                    message: format!("label {} is already defined", label.trim()),
+                });
+            }
+            text = rest.trim();
+        }
+
+        match statement(text) {
+            Ok(words) => {
+                // Only the line that crosses the end is reported
+                if address <= MEMORY_SIZE && address + words.len() > MEMORY_SIZE {
+                    errors.push(Error {
+                        line: line_number,
+                        message: format!("the image runs past the end of memory ({} words)", MEMORY_SIZE),
+                    });
+                }
+                address += words.len();
+                pending.push(Pending {
+                    line: line_number,
+                    words,
+                });
+            }
+            Err(message) => errors.push(Error {
+                line: line_number,
+                message,
+            }),
+        }
+    }
+
+    let mut image = Vec::with_capacity(address);
+    for Pending { line, words } in pending {
+        for word in words {
+            match word {
+                Word::Value(value) => image.push(value),
+                Word::Label(label) => match labels.get(&label) {
+                    Some(&address) => image.push(address as u16),
+                    None => errors.push(Error {
+                        line,
+                        message: format!("undefined label {}", label),
+                    }),
+                },
+            }
+        }
+    }
+
+    errors.sort_by_key(|e| e.line);
+    if errors.is_empty() {
+        Ok(image)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(source: &str) -> Vec<String> {
+        match assemble(source) {
+            Ok(_) => panic!("{:?} assembled", source),
+            Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn assembles_labels_and_directives() {
+        let image = assemble("start: jmp end\n.pstring \"hi\"\nend: halt").ok().unwrap();
+        assert_eq!(image, vec![6, 5, 2, 104, 105, 0]);
+    }
+
+    #[test]
+    fn reports_bad_statements() {
+        assert_eq!(errors("halt\nfrob r0"), vec!["line 2: unknown instruction frob"]);
+        assert_eq!(errors("\n\nset r0"), vec!["line 3: set takes 2 operands, found 1"]);
+        assert_eq!(errors("set r8 1"), vec!["line 1: no such register r8"]);
+        assert_eq!(errors("out 40000"), vec!["line 1: operand 40000 out of range"]);
+    }
+
+    #[test]
+    fn reports_every_error_in_line_order() {
+        let source = "a: halt\njmp nowhere\na: noop\nbogus";
+        assert_eq!(
+            errors(source),
+            vec![
+                "line 2: undefined label nowhere",
+                "line 3: label a is already defined",
+                "line 4: unknown instruction bogus",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_running_past_the_end_of_memory() {
+        let source = format!("halt\n.string \"{}\"\nhalt", "x".repeat(MEMORY_SIZE));
+        assert_eq!(errors(&source), vec!["line 2: the image runs past the end of memory (32768 words)"]);
+    }
+}
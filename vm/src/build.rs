@@ -2,7 +2,7 @@ use std::fs::File;
 use std::io::Read;
 
 fn read_num(mut f: &File, mut buf: [u8; 2]) -> Option<u16> {
-    if let Ok(_) = (&mut f).read_exact(&mut buf) {
+    if (&mut f).read_exact(&mut buf).is_ok() {
         let instruction = ((buf[1] as u16) << 8) | (buf[0] as u16);
         Some(instruction)
     } else {
@@ -22,3 +22,70 @@ pub fn read_binary(filename: &str) -> Vec<u16> {
 
     instructions
 }
+
+/// Writes `words` in the same little-endian format `read_binary` reads.
+pub fn write_binary(filename: &str, words: &[u16]) {
+    let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    std::fs::write(filename, bytes).expect("Failed to write .bin file");
+}
+
+/// Machine state saved by the `dump` command.
+pub struct Snapshot {
+    pub ip: usize,
+    pub registers: Vec<u16>,
+    pub stack: Vec<u16>,
+    pub memory: Vec<u16>,
+}
+
+/// Reads a snapshot written by `dump`, or returns what's wrong with it.
+pub fn read_snapshot(filename: &str) -> Result<Snapshot, String> {
+    let text = std::fs::read_to_string(filename).map_err(|e| format!("{}: {}", filename, e))?;
+    let sections: Vec<_> = text.split("\n\n").collect();
+
+    let section = |name: &str| {
+        sections
+            .iter()
+            .find_map(|s| s.strip_prefix(name))
+            .map(str::trim)
+            .ok_or_else(|| format!("{}: no {} section", filename, name.trim()))
+    };
+    let invalid = |name: &str| format!("{}: invalid {} section", filename, name);
+
+    let pairs: Vec<(u16, u16)> = serde_json::from_str(section("Memory\n")?).map_err(|_| invalid("Memory"))?;
+
+    let mut memory = vec![0; pairs.iter().map(|(i, _)| *i as usize + 1).max().unwrap_or(0)];
+    for (address, value) in pairs {
+        memory[address as usize] = value;
+    }
+
+    let registers: Vec<u16> = serde_json::from_str(section("Registers\n")?).map_err(|_| invalid("Registers"))?;
+    if registers.len() != 8 {
+        return Err(format!("{}: {} registers, rather than 8", filename, registers.len()));
+    }
+
+    Ok(Snapshot {
+        ip: section("IP\n")?.lines().next().and_then(|ip| ip.parse().ok()).ok_or_else(|| invalid("IP"))?,
+        registers,
+        stack: serde_json::from_str(section("Stack\n")?).map_err(|_| invalid("Stack"))?,
+        memory,
+    })
+}
+
+/// Reads memory from either a .bin file or a snapshot written by `dump`.
+pub fn read_memory(filename: &str) -> Vec<u16> {
+    let mut header = [0; 3];
+    let is_snapshot = File::open(filename)
+        .and_then(|mut f| f.read_exact(&mut header))
+        .map(|_| &header == b"IP\n")
+        .unwrap_or(false);
+
+    if is_snapshot {
+        let snapshot = read_snapshot(filename).unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        });
+        snapshot.memory
+    } else {
+        read_binary(filename)
+    }
+}
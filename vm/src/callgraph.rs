@@ -0,0 +1,290 @@
+//! Function discovery and the call graph between functions.
+
+use super::constprop;
+use super::debug::Statistics;
+use super::disasm::Analysis;
+use super::opcode::Opcode;
+use super::symbols;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+
+pub struct Function {
+    pub entry: usize,
+    pub name: String,
+    /// Every instruction reachable from `entry` without calling or tail-jumping elsewhere.
+    pub instructions: BTreeSet<usize>,
+    /// `(site, target)` for each literal or resolved call, and each jump into another function.
+    pub calls: Vec<(usize, usize)>,
+    /// Call sites whose target is in a register.
+    pub indirect: Vec<usize>,
+}
+
+impl Function {
+    /// The last address belonging to the function.
+    pub fn end(&self, analysis: &Analysis) -> usize {
+        self.instructions
+            .iter()
+            .map(|a| analysis.code[a].next() - 1)
+            .max()
+            .unwrap_or(self.entry)
+    }
+}
+
+pub struct CallGraph {
+    pub functions: BTreeMap<usize, Function>,
+    /// Where indirect calls and jumps were seen or worked out to go, by site.
+    pub targets: BTreeMap<usize, BTreeSet<usize>>,
+    /// Runtime call counts, if a profile was supplied.
+    profile: Option<Statistics>,
+}
+
+/// An edge between two functions, merging every site that makes the same call.
+struct Edge {
+    sites: Vec<usize>,
+    count: Option<usize>,
+}
+
+impl CallGraph {
+    /// Splits `analysis` into functions, starting one at each of `entries`, every labelled
+    /// address and every call target. Indirect calls observed in `profile` are added as edges too.
+    pub fn build(
+        analysis: &Analysis,
+        entries: &[usize],
+        labels: &HashMap<usize, String>,
+        profile: Option<Statistics>,
+    ) -> CallGraph {
+        let mut targets: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+
+        if let Some(profile) = &profile {
+            for (site, observed) in &profile.call_sites {
+                let observed = observed.keys().map(|&t| t as usize);
+                targets.entry(*site as usize).or_default().extend(observed);
+            }
+        }
+
+        Self::build_with(analysis, entries, labels, profile, targets)
+    }
+
+    /// Like `build`, but with the targets of indirect calls and jumps worked out by constant
+    /// propagation as well, which can find more functions and so more targets.
+    pub fn resolved(
+        analysis: &Analysis,
+        entries: &[usize],
+        labels: &HashMap<usize, String>,
+        profile: Option<Statistics>,
+    ) -> CallGraph {
+        let mut graph = Self::build(analysis, entries, labels, profile);
+
+        loop {
+            let mut targets = graph.targets.clone();
+            for (site, found) in constprop::resolve(analysis, &graph, graph.profile.as_ref()) {
+                targets.entry(site).or_default().extend(found);
+            }
+
+            if targets == graph.targets {
+                return graph;
+            }
+            graph = Self::build_with(analysis, entries, labels, graph.profile, targets);
+        }
+    }
+
+    fn build_with(
+        analysis: &Analysis,
+        entries: &[usize],
+        labels: &HashMap<usize, String>,
+        profile: Option<Statistics>,
+        targets: BTreeMap<usize, BTreeSet<usize>>,
+    ) -> CallGraph {
+        let mut starts: BTreeSet<usize> = entries.iter().chain(labels.keys()).copied().collect();
+
+        for instruction in analysis.code.values() {
+            match (instruction.opcode, instruction.target()) {
+                (Opcode::Call, Some(target)) => {
+                    starts.insert(target);
+                }
+                (Opcode::Call, None) => {
+                    starts.extend(targets.get(&instruction.address).into_iter().flatten());
+                }
+                _ => {}
+            }
+        }
+        starts.retain(|a| analysis.is_code(*a));
+
+        let functions = starts
+            .iter()
+            .map(|&entry| (entry, Self::function(analysis, entry, &starts, labels, &targets)))
+            .collect();
+
+        CallGraph {
+            functions,
+            targets,
+            profile,
+        }
+    }
+
+    fn function(
+        analysis: &Analysis,
+        entry: usize,
+        starts: &BTreeSet<usize>,
+        labels: &HashMap<usize, String>,
+        targets: &BTreeMap<usize, BTreeSet<usize>>,
+    ) -> Function {
+        let mut function = Function {
+            entry,
+            name: symbols::function_name(entry, labels),
+            instructions: BTreeSet::new(),
+            calls: Vec::new(),
+            indirect: Vec::new(),
+        };
+
+        let mut pending = vec![entry];
+
+        while let Some(address) = pending.pop() {
+            let instruction = match analysis.code.get(&address) {
+                Some(instruction) if function.instructions.insert(address) => instruction,
+                _ => continue,
+            };
+
+            if instruction.falls_through() {
+                match instruction.next() {
+                    // Running straight into another function is a tail call too
+                    next if starts.contains(&next) => function.calls.push((address, next)),
+                    next => pending.push(next),
+                }
+            }
+
+            let found = targets.get(&address).into_iter().flatten().copied();
+
+            match (instruction.opcode, instruction.target()) {
+                (Opcode::Call, Some(target)) => function.calls.push((address, target)),
+                (Opcode::Call, None) => {
+                    function.indirect.push(address);
+                    function.calls.extend(found.map(|target| (address, target)));
+                }
+                // Jumping to the start of another function is a tail call
+                (_, Some(target)) if target != entry && starts.contains(&target) => {
+                    function.calls.push((address, target))
+                }
+                (_, Some(target)) => pending.push(target),
+                _ if instruction.is_indirect() => {
+                    for target in found.filter(|t| analysis.is_code(*t)) {
+                        if target != entry && starts.contains(&target) {
+                            function.calls.push((address, target));
+                        } else {
+                            pending.push(target);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        function.calls.sort_unstable();
+        function.calls.dedup();
+        function
+    }
+
+    /// The function `address` is in. Where functions share code, the nearest entry before
+    /// `address` is the likeliest.
+    pub fn containing(&self, address: usize) -> Option<&Function> {
+        self.functions
+            .values()
+            .filter(|f| f.instructions.contains(&address))
+            .max_by_key(|f| (f.entry <= address, f.entry))
+    }
+
+    /// How many times `entry` was called, according to the profile.
+    fn count(&self, entry: usize) -> Option<usize> {
+        let profile = self.profile.as_ref()?;
+        Some(profile.calls.get(&(entry as u16)).copied().unwrap_or(0))
+    }
+
+    fn edges(&self, function: &Function) -> BTreeMap<usize, Edge> {
+        let mut edges: BTreeMap<usize, Edge> = BTreeMap::new();
+
+        for &(site, target) in &function.calls {
+            let count = self.profile.as_ref().map(|p| {
+                let sites = p.call_sites.get(&(site as u16));
+                sites.and_then(|t| t.get(&(target as u16))).copied().unwrap_or(0)
+            });
+
+            let edge = edges.entry(target).or_insert(Edge {
+                sites: Vec::new(),
+                count: count.map(|_| 0),
+            });
+            edge.sites.push(site);
+            edge.count = edge.count.zip(count).map(|(a, b)| a + b);
+        }
+
+        edges
+    }
+
+    /// Graphviz source, with edges drawn heavier the more often they were taken.
+    pub fn dot(&self, analysis: &Analysis) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph calls {{").unwrap();
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+
+        for function in self.functions.values() {
+            let mut label = format!("{}\\n{}-{}", function.name, function.entry, function.end(analysis));
+            if let Some(count) = self.count(function.entry) {
+                write!(label, "\\ncalls: {}", count).unwrap();
+            }
+
+            writeln!(out, "    f{} [label=\"{}\"];", function.entry, label.replace('"', "\\\"")).unwrap();
+        }
+
+        for function in self.functions.values() {
+            for (target, edge) in self.edges(function) {
+                let attributes = match edge.count {
+                    Some(count) => format!(
+                        "label=\"{}\", penwidth={:.1}",
+                        count,
+                        1.0 + (count as f64 + 1.0).log10()
+                    ),
+                    None if edge.sites.len() > 1 => format!("label=\"x{}\"", edge.sites.len()),
+                    None => {
+                        writeln!(out, "    f{} -> f{};", function.entry, target).unwrap();
+                        continue;
+                    }
+                };
+
+                writeln!(out, "    f{} -> f{} [{}];", function.entry, target, attributes).unwrap();
+            }
+        }
+
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    pub fn json(&self, analysis: &Analysis) -> serde_json::Value {
+        let functions: Vec<_> = self
+            .functions
+            .values()
+            .map(|function| {
+                let callees: Vec<_> = self
+                    .edges(function)
+                    .into_iter()
+                    .map(|(target, edge)| {
+                        serde_json::json!({
+                            "target": target,
+                            "sites": edge.sites,
+                            "count": edge.count,
+                        })
+                    })
+                    .collect();
+
+                serde_json::json!({
+                    "address": function.entry,
+                    "name": function.name,
+                    "end": function.end(analysis),
+                    "count": self.count(function.entry),
+                    "indirect": function.indirect,
+                    "callees": callees,
+                })
+            })
+            .collect();
+
+        serde_json::json!({ "functions": functions })
+    }
+}
@@ -0,0 +1,168 @@
+//! Basic-block control flow graphs for single functions.
+
+use super::callgraph::Function;
+use super::disasm::{Analysis, Decoded};
+use super::ir::Effect;
+use super::opcode::Opcode;
+use super::symbols;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Target {
+    Block(usize),
+    /// Control leaves for another function without returning, e.g. a tail jump.
+    Function(usize),
+    /// `Ret` or `Halt`.
+    Exit,
+    /// A jump through a register.
+    Indirect,
+}
+
+pub struct Block {
+    pub start: usize,
+    pub instructions: Vec<usize>,
+    /// Where control goes next, with the edge label for conditional jumps.
+    pub successors: Vec<(Target, &'static str)>,
+}
+
+pub struct Cfg {
+    pub entry: usize,
+    pub blocks: BTreeMap<usize, Block>,
+}
+
+fn ends_block(instruction: &Decoded) -> bool {
+    matches!(
+        instruction.lift(),
+        Some(Effect::Jump(_) | Effect::Branch { .. } | Effect::Ret | Effect::Halt)
+    )
+}
+
+impl Cfg {
+    /// Splits `function` into basic blocks at jump targets and after every branch.
+    pub fn build(analysis: &Analysis, function: &Function) -> Cfg {
+        let mut leaders = BTreeSet::from([function.entry]);
+
+        for address in &function.instructions {
+            let instruction = &analysis.code[address];
+
+            if let Some(target) = instruction.target().filter(|_| instruction.opcode != Opcode::Call) {
+                leaders.insert(target);
+            }
+            if ends_block(instruction) {
+                leaders.insert(instruction.next());
+            }
+        }
+        leaders.retain(|a| function.instructions.contains(a));
+
+        let blocks = leaders
+            .iter()
+            .map(|&start| (start, Self::block(analysis, function, &leaders, start)))
+            .collect();
+
+        Cfg {
+            entry: function.entry,
+            blocks,
+        }
+    }
+
+    fn block(analysis: &Analysis, function: &Function, leaders: &BTreeSet<usize>, start: usize) -> Block {
+        let destination = |address: usize| {
+            if function.instructions.contains(&address) {
+                Target::Block(address)
+            } else {
+                Target::Function(address)
+            }
+        };
+
+        let mut instructions = Vec::new();
+        let mut address = start;
+
+        loop {
+            let instruction = &analysis.code[&address];
+            instructions.push(address);
+
+            let next = instruction.next();
+            let target = instruction.target().map(destination).unwrap_or(Target::Indirect);
+
+            let successors = match instruction.lift() {
+                Some(Effect::Ret | Effect::Halt) => vec![(Target::Exit, "")],
+                Some(Effect::Jump(_)) => vec![(target, "")],
+                Some(Effect::Branch { nonzero: true, .. }) => vec![(target, "true"), (destination(next), "false")],
+                Some(Effect::Branch { nonzero: false, .. }) => vec![(target, "false"), (destination(next), "true")],
+                _ if leaders.contains(&next) || !function.instructions.contains(&next) => {
+                    vec![(destination(next), "")]
+                }
+                _ => {
+                    address = next;
+                    continue;
+                }
+            };
+
+            return Block {
+                start,
+                instructions,
+                successors,
+            };
+        }
+    }
+
+    /// Graphviz source with each block's disassembly inside its node.
+    pub fn dot(&self, analysis: &Analysis, labels: &HashMap<usize, String>) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph cfg {{").unwrap();
+        writeln!(out, "    label=\"{}\";", escape(&symbols::function_name(self.entry, labels))).unwrap();
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+
+        let mut external = BTreeSet::new();
+
+        for block in self.blocks.values() {
+            let mut text = String::new();
+            for address in &block.instructions {
+                let instruction = &analysis.code[address];
+                write!(text, "{:5}: {}", address, instruction.operation()).unwrap();
+
+                if let (Opcode::Call, Some(target)) = (instruction.opcode, instruction.target()) {
+                    write!(text, " ; {}", symbols::function_name(target, labels)).unwrap();
+                }
+                text.push('\n');
+            }
+
+            writeln!(out, "    b{} [label=\"{}\"];", block.start, escape(&text).replace('\n', "\\l")).unwrap();
+
+            for (target, condition) in &block.successors {
+                let node = match target {
+                    Target::Block(address) => format!("b{}", address),
+                    Target::Function(address) => format!("f{}", address),
+                    Target::Exit => "exit".to_owned(),
+                    Target::Indirect => "indirect".to_owned(),
+                };
+                external.insert(*target);
+
+                match *condition {
+                    "" => writeln!(out, "    b{} -> {};", block.start, node).unwrap(),
+                    condition => writeln!(out, "    b{} -> {} [label=\"{}\"];", block.start, node, condition).unwrap(),
+                }
+            }
+        }
+
+        for target in external {
+            match target {
+                Target::Function(address) => {
+                    let name = escape(&symbols::function_name(address, labels));
+                    writeln!(out, "    f{} [label=\"{}\", shape=ellipse];", address, name).unwrap()
+                }
+                Target::Exit => writeln!(out, "    exit [shape=oval];").unwrap(),
+                Target::Indirect => writeln!(out, "    indirect [label=\"?\", shape=oval];").unwrap(),
+                Target::Block(_) => {}
+            }
+        }
+
+        writeln!(out, "}}").unwrap();
+        out
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
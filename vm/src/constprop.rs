@@ -0,0 +1,232 @@
+//! Constant propagation through the registers: within a basic block, across a function, and
+//! from callers into callees, which is enough to work out where most indirect calls and jumps go.
+
+use super::callgraph::CallGraph;
+use super::cfg::{Cfg, Target};
+use super::convention;
+use super::dataflow::{self, ALL};
+use super::debug::Statistics;
+use super::disasm::{Analysis, Decoded};
+use super::ir::{Effect, Expr};
+use super::opcode::Operand;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Value {
+    Unknown,
+    Const(u16),
+    /// Whatever the register held when the block was entered.
+    Entry(usize),
+}
+
+pub type Registers = [Value; 8];
+
+/// The registers on entry to a block, before anything is known about them.
+pub fn entry() -> Registers {
+    [0, 1, 2, 3, 4, 5, 6, 7].map(Value::Entry)
+}
+
+/// The value of an operand.
+pub fn operand(registers: &Registers, value: Operand) -> Value {
+    match value {
+        Operand::Literal(c) => Value::Const(c),
+        Operand::Register(r) => registers[r],
+        Operand::Invalid(_) => Value::Unknown,
+    }
+}
+
+/// Updates `registers` with the effect of `instruction`.
+pub fn step(registers: &mut Registers, instruction: &Decoded) {
+    match instruction.lift() {
+        Some(Effect::Assign(r, Expr::Copy(value))) => registers[r] = operand(registers, value),
+        Some(Effect::Assign(r, expr)) => {
+            let computed = expr.evaluate(|v| match operand(registers, v) {
+                Value::Const(c) => Some(c),
+                _ => None,
+            });
+            registers[r] = computed.map_or(Value::Unknown, Value::Const);
+        }
+        // The callee may clobber anything
+        Some(Effect::Call(_)) => *registers = [Value::Unknown; 8],
+        Some(effect) => {
+            if let Some(r) = effect.destination() {
+                registers[r] = Value::Unknown;
+            }
+        }
+        None => {}
+    }
+}
+
+/// The registers just before each of `instructions`, which make up one basic block.
+pub fn through_block(analysis: &Analysis, instructions: &[usize]) -> Vec<Registers> {
+    let mut registers = entry();
+
+    instructions
+        .iter()
+        .map(|address| {
+            let before = registers;
+            step(&mut registers, &analysis.code[address]);
+            before
+        })
+        .collect()
+}
+
+fn meet(a: &Registers, b: &Registers) -> Registers {
+    let mut registers = *a;
+    for (r, value) in registers.iter_mut().enumerate() {
+        if *value != b[r] {
+            *value = Value::Unknown;
+        }
+    }
+    registers
+}
+
+/// The registers just before every instruction in `cfg`, merging where paths meet. A call
+/// keeps the registers `preserved` says it doesn't change.
+pub fn through_function(
+    analysis: &Analysis,
+    cfg: &Cfg,
+    preserved: impl Fn(&Decoded) -> dataflow::Registers,
+) -> BTreeMap<usize, Registers> {
+    let mut incoming: BTreeMap<usize, Registers> = BTreeMap::new();
+    let mut before = BTreeMap::new();
+    let mut pending = vec![(cfg.entry, entry())];
+
+    while let Some((start, registers)) = pending.pop() {
+        let mut registers = match incoming.get(&start) {
+            Some(old) if meet(old, &registers) == *old => continue,
+            Some(old) => meet(old, &registers),
+            None => registers,
+        };
+        incoming.insert(start, registers);
+
+        let block = &cfg.blocks[&start];
+        for address in &block.instructions {
+            let instruction = &analysis.code[address];
+            before.insert(*address, registers);
+
+            if let Some(Effect::Call(_)) = instruction.lift() {
+                let kept = preserved(instruction);
+                for (r, value) in registers.iter_mut().enumerate() {
+                    if !dataflow::contains(kept, r) {
+                        *value = Value::Unknown;
+                    }
+                }
+            } else {
+                step(&mut registers, instruction);
+            }
+        }
+
+        for (target, _) in &block.successors {
+            if let Target::Block(b) = target {
+                pending.push((*b, registers));
+            }
+        }
+    }
+
+    before
+}
+
+/// Where each indirect call and jump in `graph` may go, by site: runtime targets from
+/// `profile`, constants that reach the site, and constants callers pass in for a register
+/// that reaches it unchanged from its function's entry.
+///
+/// This is a guess rather than a proof. Before a call's targets are known it's taken to
+/// preserve every register, and a target, once found, is never dropped.
+pub fn resolve(analysis: &Analysis, graph: &CallGraph, profile: Option<&Statistics>) -> BTreeMap<usize, BTreeSet<usize>> {
+    let signatures = convention::infer(analysis, graph);
+    let mut targets: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+
+    if let Some(profile) = profile {
+        for site in &analysis.unresolved {
+            let observed = profile.call_sites.get(&(*site as u16)).into_iter();
+            let observed = observed.chain(profile.jumps.get(&(*site as u16)));
+            let observed = observed.flat_map(|t| t.keys()).map(|&t| t as usize);
+            targets.entry(*site).or_default().extend(observed);
+        }
+    }
+
+    loop {
+        let preserved = |instruction: &Decoded| {
+            let callees: Vec<usize> = match instruction.target() {
+                Some(target) => vec![target],
+                None => targets.get(&instruction.address).into_iter().flatten().copied().collect(),
+            };
+            callees.iter().fold(ALL, |kept, c| kept & !signatures.get(c).map(|s| s.clobbers).unwrap_or(ALL))
+        };
+
+        // The registers at each site, with the function it's in
+        let mut registers = BTreeMap::new();
+        for function in graph.functions.values() {
+            let cfg = Cfg::build(analysis, function);
+            for (address, before) in through_function(analysis, &cfg, preserved) {
+                registers.insert(address, (function.entry, before));
+            }
+        }
+
+        // Call sites and tail jumps into each function, including ones resolved so far
+        let mut callers: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for function in graph.functions.values() {
+            for &(site, target) in &function.calls {
+                callers.entry(target).or_default().push(site);
+            }
+        }
+        for (&site, found) in &targets {
+            for &target in found {
+                callers.entry(target).or_default().push(site);
+            }
+        }
+
+        let mut changed = false;
+        for site in &analysis.unresolved {
+            let (function, before) = match registers.get(site) {
+                Some(registers) => registers,
+                None => continue,
+            };
+            let target = match analysis.code[site].lift().and_then(|e| e.target()) {
+                Some(target) => target,
+                None => continue,
+            };
+
+            let mut found = BTreeSet::new();
+            let mut visited = BTreeSet::new();
+            values(operand(before, target), *function, &registers, &callers, &mut visited, &mut found);
+
+            let known = targets.entry(*site).or_default();
+            for target in found {
+                changed |= known.insert(target);
+            }
+        }
+
+        if !changed {
+            targets.retain(|_, found| !found.is_empty());
+            return targets;
+        }
+    }
+}
+
+/// Adds the constants `value` may be to `found`, following a register's value on entry to
+/// `function` back to each of its callers.
+fn values(
+    value: Value,
+    function: usize,
+    registers: &BTreeMap<usize, (usize, Registers)>,
+    callers: &BTreeMap<usize, Vec<usize>>,
+    visited: &mut BTreeSet<(usize, usize)>,
+    found: &mut BTreeSet<usize>,
+) {
+    let r = match value {
+        Value::Const(c) => {
+            found.insert(c as usize);
+            return;
+        }
+        Value::Entry(r) if visited.insert((function, r)) => r,
+        _ => return,
+    };
+
+    for site in callers.get(&function).into_iter().flatten() {
+        if let Some((caller, before)) = registers.get(site) {
+            values(before[r], *caller, registers, callers, visited, found);
+        }
+    }
+}
@@ -0,0 +1,386 @@
+//! Inferring each function's calling convention from how it uses registers.
+//!
+//! Arguments are registers read before they're written, results are registers written and
+//! then read by a caller after the call returns, and saved registers are pushed on the way
+//! in and popped on the way out. The analysis runs over the whole call graph, since what a
+//! function reads includes what its callees read, and its results depend on its callers.
+
+use super::callgraph::{CallGraph, Function};
+use super::cfg::{Block, Cfg, Target};
+use super::dataflow::{self, Registers, ALL};
+use super::disasm::Analysis;
+use super::opcode::Opcode;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Signature {
+    pub arguments: Registers,
+    pub results: Registers,
+    pub saved: Registers,
+    /// Registers the function may change, as seen by its caller.
+    pub clobbers: Registers,
+}
+
+/// The registers in `registers`, as `r0`, `r1`...
+pub fn list(registers: Registers) -> Vec<String> {
+    (0..8).filter(|&r| dataflow::contains(registers, r)).map(|r| format!("r{}", r)).collect()
+}
+
+impl Signature {
+    /// The arguments and results, e.g. `(r0, r1) -> r1`.
+    pub fn prototype(&self) -> String {
+        match self.results {
+            0 => format!("({})", list(self.arguments).join(", ")),
+            results => format!("({}) -> {}", list(self.arguments).join(", "), list(results).join(", ")),
+        }
+    }
+
+    /// E.g. `saves r3 r4`, or nothing if no registers are saved.
+    pub fn saves(&self) -> Option<String> {
+        Some(self.saved).filter(|&s| s != 0).map(|s| format!("saves {}", list(s).join(" ")))
+    }
+
+    /// Reads the form `Display` writes, e.g. `(r0, r1) -> r1, saves r3 r4`. Anything not
+    /// saved is taken to be clobbered.
+    pub fn parse(text: &str) -> Option<Signature> {
+        let (prototype, saved) = match text.split_once(", saves ") {
+            Some((prototype, saved)) => (prototype, parse_list(saved.split(' '))?),
+            None => (text, 0),
+        };
+        let (arguments, results) = match prototype.split_once(" -> ") {
+            Some((arguments, results)) => (arguments, parse_list(results.split(", "))?),
+            None => (prototype, 0),
+        };
+        let arguments = arguments.strip_prefix('(')?.strip_suffix(')')?;
+
+        Some(Signature {
+            arguments: parse_list(arguments.split(", ").filter(|a| !a.is_empty()))?,
+            results,
+            saved,
+            clobbers: ALL & !saved,
+        })
+    }
+}
+
+fn parse_list<'a>(registers: impl Iterator<Item = &'a str>) -> Option<Registers> {
+    registers.map(|r| r.strip_prefix('r')?.parse::<usize>().ok().filter(|&r| r < 8)).try_fold(0, |set, r| Some(set | 1 << r?))
+}
+
+impl std::fmt::Display for Signature {
+    /// E.g. `(r0, r1) -> r1, saves r3 r4`.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.prototype())?;
+        if let Some(saves) = self.saves() {
+            write!(f, ", {}", saves)?;
+        }
+        Ok(())
+    }
+}
+
+/// What's known about one function while the analysis runs.
+struct Facts<'a> {
+    function: &'a Function,
+    cfg: Cfg,
+    /// The `Push`es that save registers on entry, which don't count as reads.
+    prologue: BTreeSet<usize>,
+    saved: Registers,
+    /// Registers the function might change, as seen by its caller.
+    clobbers: Registers,
+    /// Registers the function writes on every path that returns.
+    writes: Registers,
+    arguments: Registers,
+    results: Registers,
+}
+
+impl<'a> Facts<'a> {
+    fn new(analysis: &Analysis, function: &'a Function) -> Facts<'a> {
+        let cfg = Cfg::build(analysis, function);
+        let mut prologue = BTreeSet::new();
+        let mut pushed = 0;
+
+        // Registers pushed at the top of the entry block, before anything can change them
+        for &address in &cfg.blocks[&cfg.entry].instructions {
+            let instruction = &analysis.code[&address];
+            match (instruction.opcode, instruction.operands.first().and_then(|o| o.register())) {
+                (Opcode::Push, Some(r)) => {
+                    prologue.insert(address);
+                    pushed |= 1 << r;
+                }
+                _ => break,
+            }
+        }
+
+        // ...and popped back again somewhere
+        let popped = function
+            .instructions
+            .iter()
+            .map(|a| &analysis.code[a])
+            .filter(|i| i.opcode == Opcode::Pop)
+            .fold(0, |set, i| set | dataflow::writes(i));
+
+        Facts {
+            function,
+            cfg,
+            prologue,
+            saved: pushed & popped,
+            clobbers: 0,
+            writes: 0,
+            arguments: 0,
+            results: 0,
+        }
+    }
+}
+
+struct Inference<'a> {
+    analysis: &'a Analysis,
+    facts: BTreeMap<usize, Facts<'a>>,
+    /// Functions whose address is loaded as a value, so they may be the target of any
+    /// indirect call.
+    taken: BTreeSet<usize>,
+}
+
+impl<'a> Inference<'a> {
+    /// The union of `field` over every function an indirect call might reach.
+    fn indirect(&self, field: impl Fn(&Facts) -> Registers) -> Registers {
+        self.taken.iter().fold(0, |set, f| set | field(&self.facts[f]))
+    }
+
+    fn callee(&self, address: usize) -> Option<&Facts<'a>> {
+        let target = self.analysis.code[&address].target()?;
+        self.facts.get(&target)
+    }
+
+    /// The registers an instruction in `function` reads and definitely writes, taking calls
+    /// into account.
+    fn effect(&self, function: &Facts, address: usize) -> (Registers, Registers) {
+        let instruction = &self.analysis.code[&address];
+
+        match instruction.opcode {
+            _ if function.prologue.contains(&address) => (0, 0),
+            Opcode::Call => match self.callee(address) {
+                Some(callee) => (callee.arguments | dataflow::reads(instruction), callee.writes),
+                None => (self.indirect(|f| f.arguments) | dataflow::reads(instruction), 0),
+            },
+            Opcode::Ret => (0, 0),
+            _ => (dataflow::reads(instruction), dataflow::writes(instruction)),
+        }
+    }
+
+    /// The registers live when control leaves `block` for `target`.
+    fn exit(&self, function: &Facts, block: &Block, target: Target) -> Registers {
+        let last = &self.analysis.code[block.instructions.last().unwrap()];
+
+        match target {
+            Target::Exit if last.opcode == Opcode::Halt => 0,
+            // A tail call returns straight to our caller
+            Target::Function(t) => function.results | self.facts.get(&t).map(|f| f.arguments).unwrap_or(ALL),
+            _ => function.results,
+        }
+    }
+
+    fn liveness(&self, function: &Facts) -> BTreeMap<usize, (Registers, Registers)> {
+        dataflow::solve(
+            &function.cfg,
+            |a| self.effect(function, a),
+            |b, t| self.exit(function, b, t),
+        )
+    }
+
+    /// Registers changed anywhere in the function or its callees, less the saved ones.
+    fn clobbers(&self, function: &Facts) -> Registers {
+        let mut clobbers = 0;
+
+        for address in &function.function.instructions {
+            let instruction = &self.analysis.code[address];
+            clobbers |= dataflow::writes(instruction);
+
+            if instruction.opcode == Opcode::Call {
+                clobbers |= match self.callee(*address) {
+                    Some(callee) => callee.clobbers,
+                    None => self.indirect(|f| f.clobbers),
+                };
+            }
+        }
+        for &(_, target) in &function.function.calls {
+            clobbers |= self.facts.get(&target).map(|f| f.clobbers).unwrap_or(0);
+        }
+
+        clobbers & !function.saved
+    }
+
+    /// Registers written on every path through the function that returns, by going forward
+    /// from the entry and intersecting where paths meet.
+    fn writes(&self, function: &Facts) -> Registers {
+        let cfg = &function.cfg;
+        let mut written: BTreeMap<usize, Registers> = BTreeMap::new();
+        let mut returned: Option<Registers> = None;
+        let mut pending = vec![(cfg.entry, 0)];
+
+        while let Some((start, incoming)) = pending.pop() {
+            let before = match written.get(&start) {
+                Some(&w) if w & incoming == w => continue,
+                Some(&w) => w & incoming,
+                None => incoming,
+            };
+            written.insert(start, before);
+
+            let block = &cfg.blocks[&start];
+            let after = block.instructions.iter().fold(before, |set, &a| set | self.effect(function, a).1);
+            let last = &self.analysis.code[block.instructions.last().unwrap()];
+
+            for (target, _) in &block.successors {
+                let at_exit = match target {
+                    Target::Block(b) => {
+                        pending.push((*b, after));
+                        continue;
+                    }
+                    Target::Exit if last.opcode == Opcode::Halt => continue,
+                    Target::Function(t) => after | self.facts.get(t).map(|f| f.writes).unwrap_or(0),
+                    _ => after,
+                };
+                returned = Some(returned.map_or(at_exit, |r| r & at_exit));
+            }
+        }
+
+        // A function that never returns may as well write everything
+        returned.unwrap_or(ALL) & !function.saved
+    }
+
+    /// What callers read of each function's registers once it returns, by function.
+    fn demand(&self) -> BTreeMap<usize, Registers> {
+        let mut demand: BTreeMap<usize, Registers> = BTreeMap::new();
+
+        for function in self.facts.values() {
+            let live = self.liveness(function);
+
+            for block in function.cfg.blocks.values() {
+                let after = dataflow::live_after(block, live[&block.start].1, |a| self.effect(function, a));
+
+                for (address, live) in after {
+                    if self.analysis.code[&address].opcode != Opcode::Call {
+                        continue;
+                    }
+                    let targets = match self.analysis.code[&address].target() {
+                        Some(target) => vec![target],
+                        None => self.taken.iter().copied().collect(),
+                    };
+                    for target in targets {
+                        *demand.entry(target).or_default() |= live;
+                    }
+                }
+
+                for (target, _) in &block.successors {
+                    if let Target::Function(t) = target {
+                        *demand.entry(*t).or_default() |= function.results;
+                    }
+                }
+            }
+        }
+
+        demand
+    }
+
+    fn update(&mut self, field: impl for<'b> Fn(&'b mut Facts<'a>) -> &'b mut Registers, values: BTreeMap<usize, Registers>) -> bool {
+        let mut changed = false;
+
+        for (entry, value) in values {
+            if let Some(facts) = self.facts.get_mut(&entry) {
+                let old = field(facts);
+                changed |= *old != value;
+                *old = value;
+            }
+        }
+
+        changed
+    }
+
+    /// Repeats `compute` for every function until nothing changes.
+    fn settle(
+        &mut self,
+        compute: impl Fn(&Self, &Facts) -> Registers,
+        field: impl for<'b> Fn(&'b mut Facts<'a>) -> &'b mut Registers + Copy,
+    ) {
+        loop {
+            let values = self.facts.iter().map(|(&e, f)| (e, compute(self, f))).collect();
+            if !self.update(field, values) {
+                return;
+            }
+        }
+    }
+}
+
+/// The signature of every function in `graph`, by entry address.
+pub fn infer(analysis: &Analysis, graph: &CallGraph) -> BTreeMap<usize, Signature> {
+    let facts: BTreeMap<usize, Facts> = graph
+        .functions
+        .values()
+        .map(|function| (function.entry, Facts::new(analysis, function)))
+        .collect();
+
+    // Zero is far more often a plain number than the address of the entry point
+    let taken: BTreeSet<usize> = analysis
+        .code
+        .values()
+        .filter(|i| !matches!(i.opcode, Opcode::Call | Opcode::Jmp | Opcode::JmpIfTrue | Opcode::JmpIfFalse))
+        .flat_map(|i| i.operands.iter().filter_map(|o| o.literal()).map(usize::from))
+        .filter(|a| *a != 0 && facts.contains_key(a))
+        .collect();
+
+    // Registers live into a function nothing calls, like the entry point, are just whatever
+    // they start as rather than arguments
+    let called: BTreeSet<usize> = analysis
+        .code
+        .values()
+        .filter_map(|i| i.target())
+        .chain(graph.targets.values().flatten().copied())
+        .chain(taken.iter().copied())
+        .filter(|&e| e != 0)
+        .collect();
+
+    let mut inference = Inference {
+        analysis,
+        facts,
+        taken,
+    };
+
+    inference.settle(|i, f| i.clobbers(f), |f| &mut f.clobbers);
+    inference.settle(|i, f| i.writes(f), |f| &mut f.writes);
+
+    // Arguments depend on results (a result the function doesn't always write passes
+    // through from its caller) and results on what callers read, so iterate the two together
+    loop {
+        let demand = inference.demand();
+        let results = inference
+            .facts
+            .iter()
+            .map(|(&e, f)| (e, f.clobbers & demand.get(&e).copied().unwrap_or(0)))
+            .collect();
+        let arguments = inference
+            .facts
+            .iter()
+            .map(|(&e, f)| match called.contains(&e) {
+                true => (e, inference.liveness(f)[&f.cfg.entry].0),
+                false => (e, 0),
+            })
+            .collect();
+
+        let changed = inference.update(|f| &mut f.results, results);
+        if !inference.update(|f| &mut f.arguments, arguments) && !changed {
+            break;
+        }
+    }
+
+    inference
+        .facts
+        .iter()
+        .map(|(&entry, f)| {
+            let signature = Signature {
+                arguments: f.arguments,
+                results: f.results,
+                saved: f.saved,
+                clobbers: f.clobbers,
+            };
+            (entry, signature)
+        })
+        .collect()
+}
@@ -0,0 +1,673 @@
+//! A Debug Adapter Protocol server over stdio, so editors can debug Synacor programs.
+//!
+//! Launching writes a linear disassembly listing of the program (one instruction per
+//! line) which serves as the "source" for stack frames and line breakpoints.
+//! Breakpoints can also be set by address (instruction breakpoints) or by label
+//! (function breakpoints). Game output is forwarded as `output` events, and game
+//! input comes from the launch `inputScript` or from the debug console: anything
+//! typed there is queued as a line of input, unless it starts with `!`, in which
+//! case it runs as a regular debugger command.
+//!
+//! Requests are read on their own thread, so a `pause` can interrupt a running program.
+
+use super::build;
+use super::debug::{Debugger, Resume, Stop};
+use super::disasm;
+use super::exec::State;
+use super::symbols;
+use serde_json::{json, Value};
+use std::collections::{HashSet, VecDeque};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::mpsc::{self, Receiver};
+
+const REGISTERS_REFERENCE: i64 = 1;
+const STACK_REFERENCE: i64 = 2;
+
+struct Program {
+    state: State,
+    listing_path: String,
+    listing: Vec<(usize, String)>,
+    stop_on_entry: bool,
+}
+
+impl Program {
+    fn line_for(&self, address: usize) -> usize {
+        match self.listing.binary_search_by_key(&address, |(a, _)| *a) {
+            Ok(i) => i + 1,
+            Err(i) => i.max(1),
+        }
+    }
+
+    fn address_for(&self, line: usize) -> Option<usize> {
+        self.listing.get(line.checked_sub(1)?).map(|(a, _)| *a)
+    }
+
+    fn source(&self) -> Value {
+        json!({ "name": "listing", "path": self.listing_path })
+    }
+}
+
+struct Server {
+    seq: i64,
+    requests: Receiver<Value>,
+    /// Requests that arrived while the program was running, to be handled once it stops.
+    pending: VecDeque<Value>,
+    output: Box<dyn Write>,
+    debugger: Debugger,
+    program: Option<Program>,
+    source_breakpoints: HashSet<u16>,
+    function_breakpoints: HashSet<u16>,
+    instruction_breakpoints: HashSet<u16>,
+}
+
+fn read_message(input: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+fn format_address(address: usize) -> String {
+    format!("0x{:04x}", address)
+}
+
+fn parse_address(reference: &str) -> Option<usize> {
+    match reference.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => reference.parse().ok(),
+    }
+}
+
+impl Server {
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+
+        let body = message.to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.output.flush().unwrap();
+    }
+
+    /// The next request to handle, or `None` once the client has gone.
+    fn next_request(&mut self) -> Option<Value> {
+        self.pending.pop_front().or_else(|| self.requests.recv().ok())
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }));
+    }
+
+    fn fail(&mut self, request: &Value, message: &str) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }));
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<&str>) {
+        let mut body = json!({ "reason": reason, "threadId": 1, "allThreadsStopped": true });
+
+        if let Some(text) = text {
+            body["description"] = json!(text);
+            body["text"] = json!(text);
+        }
+
+        self.event("stopped", body);
+    }
+
+    fn flush_output(&mut self) {
+        let output = match &mut self.program {
+            Some(program) if !program.state.output.is_empty() => std::mem::take(&mut program.state.output),
+            _ => return,
+        };
+
+        self.event("output", json!({ "category": "stdout", "output": output }));
+    }
+
+    fn update_breakpoints(&mut self) {
+        self.debugger.breakpoints = self
+            .source_breakpoints
+            .iter()
+            .chain(self.function_breakpoints.iter())
+            .chain(self.instruction_breakpoints.iter())
+            .copied()
+            .collect();
+    }
+
+    /// The name of the function a frame is executing in, given the `Call` that entered it.
+    fn function_name(&self, state: &State, call_site: Option<u16>) -> String {
+        let target = match call_site {
+            Some(site) => state.instructions.get(&(site + 1)).copied().unwrap_or(0),
+            None => 0,
+        };
+
+        if target > 32767 {
+            "<indirect>".to_owned()
+        } else {
+            symbols::function_name(target as usize, &self.debugger.labels)
+        }
+    }
+
+    fn run(&mut self, resume: Resume) {
+        let program = match self.program.as_mut() {
+            Some(program) => program,
+            None => return,
+        };
+
+        let (requests, pending) = (&self.requests, &mut self.pending);
+        let mut interrupted = || {
+            let mut stop = false;
+            while let Ok(request) = requests.try_recv() {
+                stop |= matches!(request["command"].as_str(), Some("pause" | "disconnect" | "terminate"));
+                pending.push_back(request);
+            }
+            stop
+        };
+
+        let stop = self.debugger.resume(&mut program.state, resume, &mut interrupted);
+        self.flush_output();
+
+        // The pause is answered before the `stopped` event it caused
+        if let Stop::Interrupted = stop {
+            let pauses: Vec<_> = self.pending.iter().filter(|r| r["command"] == "pause").cloned().collect();
+            self.pending.retain(|r| r["command"] != "pause");
+            for pause in pauses {
+                self.respond(&pause, json!({}));
+            }
+        }
+
+        match stop {
+            Stop::Step => self.stopped("step", None),
+            Stop::Breakpoint => self.stopped("breakpoint", None),
+            Stop::Interrupted => self.stopped("pause", None),
+            Stop::AwaitingInput => self.stopped("pause", Some("Waiting for input")),
+            Stop::InvalidOpcode => self.stopped("exception", Some("Unknown opcode")),
+            Stop::Halted => {
+                self.event("exited", json!({ "exitCode": 0 }));
+                self.event("terminated", json!({}));
+            }
+        }
+    }
+
+    fn launch(&mut self, request: &Value) {
+        let args = &request["arguments"];
+
+        let path = match args["program"].as_str() {
+            Some(path) if std::path::Path::new(path).is_file() => path,
+            _ => return self.fail(request, "`program` must point at a .bin file"),
+        };
+
+        let instructions = build::read_binary(path);
+        let listing = disasm::listing(&instructions);
+        let listing_path = args["listing"]
+            .as_str()
+            .map(str::to_owned)
+            .unwrap_or_else(|| format!("{}.dis", path));
+
+        let text: Vec<_> = listing.iter().map(|(_, line)| line.as_str()).collect();
+        if std::fs::write(&listing_path, text.join("\n") + "\n").is_err() {
+            return self.fail(request, "Couldn't write the disassembly listing");
+        }
+
+        let mut state = State::build(instructions);
+        state.echo = false;
+        state.interactive = false;
+
+        if let Some(script) = args["inputScript"].as_str() {
+            match std::fs::read_to_string(script) {
+                Ok(script) => state.input.extend(script.lines().map(str::to_owned)),
+                Err(_) => return self.fail(request, "Couldn't read `inputScript`"),
+            }
+        }
+
+        self.program = Some(Program {
+            state,
+            listing_path,
+            listing,
+            stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+        });
+
+        self.respond(request, json!({}));
+        self.event("initialized", json!({}));
+    }
+
+    fn set_breakpoints(&mut self, request: &Value) {
+        let program = match &self.program {
+            Some(program) => program,
+            None => return self.fail(request, "No program has been launched"),
+        };
+
+        let mut addresses = HashSet::new();
+        let mut breakpoints = Vec::new();
+
+        for breakpoint in request["arguments"]["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+
+            match program.address_for(line) {
+                Some(address) => {
+                    addresses.insert(address as u16);
+                    breakpoints.push(json!({ "verified": true, "line": line, "source": program.source() }));
+                }
+                None => breakpoints.push(json!({ "verified": false, "line": line })),
+            }
+        }
+
+        self.source_breakpoints = addresses;
+        self.update_breakpoints();
+        self.respond(request, json!({ "breakpoints": breakpoints }));
+    }
+
+    fn set_function_breakpoints(&mut self, request: &Value) {
+        let mut addresses = HashSet::new();
+        let mut breakpoints = Vec::new();
+
+        for breakpoint in request["arguments"]["breakpoints"].as_array().into_iter().flatten() {
+            let name = breakpoint["name"].as_str().unwrap_or("");
+            let lowercase = name.to_lowercase();
+
+            let matches: Vec<usize> = match parse_address(name) {
+                Some(address) => vec![address],
+                None => self
+                    .debugger
+                    .labels
+                    .iter()
+                    .filter(|(_, label)| label.to_lowercase().starts_with(&lowercase))
+                    .map(|(address, _)| *address)
+                    .collect(),
+            };
+
+            addresses.extend(matches.iter().map(|&a| a as u16));
+            breakpoints.push(json!({
+                "verified": !matches.is_empty(),
+                "instructionReference": matches.first().map(|&a| format_address(a)),
+            }));
+        }
+
+        self.function_breakpoints = addresses;
+        self.update_breakpoints();
+        self.respond(request, json!({ "breakpoints": breakpoints }));
+    }
+
+    fn set_instruction_breakpoints(&mut self, request: &Value) {
+        let mut addresses = HashSet::new();
+        let mut breakpoints = Vec::new();
+
+        for breakpoint in request["arguments"]["breakpoints"].as_array().into_iter().flatten() {
+            let reference = breakpoint["instructionReference"].as_str().and_then(parse_address);
+            let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+            let address = reference.map(|a| a as i64 + offset).filter(|a| (0..32768).contains(a));
+
+            if let Some(address) = address {
+                addresses.insert(address as u16);
+            }
+
+            breakpoints.push(json!({
+                "verified": address.is_some(),
+                "instructionReference": address.map(|a| format_address(a as usize)),
+            }));
+        }
+
+        self.instruction_breakpoints = addresses;
+        self.update_breakpoints();
+        self.respond(request, json!({ "breakpoints": breakpoints }));
+    }
+
+    fn stack_trace(&mut self, request: &Value) {
+        let program = match &self.program {
+            Some(program) => program,
+            None => return self.fail(request, "No program has been launched"),
+        };
+
+        let state = &program.state;
+        let pcs = std::iter::once(state.ip).chain(state.call_stack.iter().rev().map(|&s| s as usize));
+        let callers = state
+            .call_stack
+            .iter()
+            .rev()
+            .map(|&s| Some(s))
+            .chain(std::iter::once(None));
+
+        let frames: Vec<_> = pcs
+            .zip(callers)
+            .enumerate()
+            .map(|(id, (pc, caller))| {
+                json!({
+                    "id": id,
+                    "name": self.function_name(state, caller),
+                    "source": program.source(),
+                    "line": program.line_for(pc),
+                    "column": 1,
+                    "instructionPointerReference": format_address(pc),
+                })
+            })
+            .collect();
+
+        let total = frames.len();
+        self.respond(request, json!({ "stackFrames": frames, "totalFrames": total }));
+    }
+
+    fn variables(&mut self, request: &Value) {
+        let state = match &self.program {
+            Some(program) => &program.state,
+            None => return self.fail(request, "No program has been launched"),
+        };
+
+        let variables: Vec<_> = match request["arguments"]["variablesReference"].as_i64() {
+            Some(REGISTERS_REFERENCE) => state
+                .registers
+                .iter()
+                .enumerate()
+                .map(|(i, v)| (format!("r{}", i), v.to_string()))
+                .chain(std::iter::once(("ip".to_owned(), state.ip.to_string())))
+                .collect(),
+            Some(STACK_REFERENCE) => state
+                .stack
+                .iter()
+                .rev()
+                .enumerate()
+                .map(|(i, v)| (format!("[{}]", i), v.to_string()))
+                .collect(),
+            _ => vec![],
+        };
+
+        let variables: Vec<_> = variables
+            .into_iter()
+            .map(|(name, value)| json!({ "name": name, "value": value, "variablesReference": 0 }))
+            .collect();
+
+        self.respond(request, json!({ "variables": variables }));
+    }
+
+    fn set_variable(&mut self, request: &Value) {
+        let args = &request["arguments"];
+        let name = args["name"].as_str().unwrap_or("");
+        let value = args["value"].as_str().and_then(|v| v.parse::<u16>().ok());
+        let register = name.strip_prefix('r').and_then(|r| r.parse::<usize>().ok());
+
+        match (&mut self.program, args["variablesReference"].as_i64(), register, value) {
+            (Some(program), Some(REGISTERS_REFERENCE), Some(register), Some(value)) if register < 8 => {
+                program.state.registers[register] = value;
+                self.respond(request, json!({ "value": value.to_string() }));
+            }
+            _ => self.fail(request, "Only registers can be set, to a number"),
+        }
+    }
+
+    fn disassemble(&mut self, request: &Value) {
+        let program = match &self.program {
+            Some(program) => program,
+            None => return self.fail(request, "No program has been launched"),
+        };
+
+        let args = &request["arguments"];
+        let address = args["memoryReference"].as_str().and_then(parse_address).unwrap_or(0) as i64;
+        let address = address + args["offset"].as_i64().unwrap_or(0);
+        let count = args["instructionCount"].as_i64().unwrap_or(0);
+
+        let start = match program.listing.binary_search_by_key(&address, |(a, _)| *a as i64) {
+            Ok(i) | Err(i) => i as i64,
+        } + args["instructionOffset"].as_i64().unwrap_or(0);
+
+        let instructions: Vec<_> = (start..start + count)
+            .map(|i| match program.listing.get(i as usize).filter(|_| i >= 0) {
+                Some((address, line)) => {
+                    let text = line.split_once(": ").map(|(_, t)| t).unwrap_or(line);
+                    json!({
+                        "address": format_address(*address),
+                        "instruction": text,
+                        "symbol": self.debugger.labels.get(address).map(|l| symbols::short_label(l)),
+                        "location": program.source(),
+                        "line": i + 1,
+                    })
+                }
+                None => json!({
+                    "address": format_address(i.max(0) as usize),
+                    "instruction": "??",
+                    "presentationHint": "invalid",
+                }),
+            })
+            .collect();
+
+        self.respond(request, json!({ "instructions": instructions }));
+    }
+
+    fn evaluate(&mut self, request: &Value) {
+        let program = match self.program.as_mut() {
+            Some(program) => program,
+            None => return self.fail(request, "No program has been launched"),
+        };
+
+        let expression = request["arguments"]["expression"].as_str().unwrap_or("");
+
+        let result = match expression.strip_prefix('!') {
+            Some(command) => {
+                let command: Vec<_> = command.split_ascii_whitespace().collect();
+                let mut output = String::new();
+                self.debugger.run_command(&mut program.state, &command, &mut output);
+                output
+            }
+            None => {
+                program.state.input.push_back(expression.to_owned());
+                format!("Queued input: {}", expression)
+            }
+        };
+
+        self.respond(request, json!({ "result": result, "variablesReference": 0 }));
+    }
+
+    /// Handles a single request, returning `false` once the session is over.
+    fn handle(&mut self, request: &Value) -> bool {
+        match request["command"].as_str().unwrap_or("") {
+            "initialize" => self.respond(
+                request,
+                json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsFunctionBreakpoints": true,
+                    "supportsInstructionBreakpoints": true,
+                    "supportsDisassembleRequest": true,
+                    "supportsSetVariable": true,
+                    "supportsSteppingGranularity": false,
+                    "supportsTerminateRequest": true,
+                }),
+            ),
+            "launch" => self.launch(request),
+            "setBreakpoints" => self.set_breakpoints(request),
+            "setFunctionBreakpoints" => self.set_function_breakpoints(request),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(request),
+            "setExceptionBreakpoints" => self.respond(request, json!({ "breakpoints": [] })),
+            "configurationDone" => {
+                self.respond(request, json!({}));
+
+                match &self.program {
+                    Some(program) if program.stop_on_entry => self.stopped("entry", None),
+                    _ => self.run(Resume::Continue),
+                }
+            }
+            "threads" => self.respond(request, json!({ "threads": [{ "id": 1, "name": "vm" }] })),
+            "stackTrace" => self.stack_trace(request),
+            "scopes" => self.respond(
+                request,
+                json!({ "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                    { "name": "Stack", "variablesReference": STACK_REFERENCE, "expensive": false },
+                ]}),
+            ),
+            "variables" => self.variables(request),
+            "setVariable" => self.set_variable(request),
+            "disassemble" => self.disassemble(request),
+            "evaluate" => self.evaluate(request),
+            "continue" => {
+                self.respond(request, json!({ "allThreadsContinued": true }));
+                self.run(Resume::Continue);
+            }
+            "next" => {
+                self.respond(request, json!({}));
+                self.run(Resume::StepOver);
+            }
+            "stepIn" => {
+                self.respond(request, json!({}));
+                self.run(Resume::Step);
+            }
+            "stepOut" => {
+                self.respond(request, json!({}));
+                self.run(Resume::StepOut);
+            }
+            // A pause while the program runs is answered by `run`, so this one came while it
+            // was stopped and there's nothing to do
+            "pause" => self.respond(request, json!({})),
+            "disconnect" | "terminate" => {
+                self.respond(request, json!({}));
+                return false;
+            }
+            _ => self.fail(request, "Unsupported request"),
+        }
+
+        true
+    }
+}
+
+/// Serves a single debug session over stdin/stdout.
+pub fn serve() {
+    session(std::io::stdin(), std::io::stdout());
+}
+
+fn session(input: impl Read + Send + 'static, output: impl Write + 'static) {
+    let (sender, requests) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut input = BufReader::new(input);
+        while let Some(request) = read_message(&mut input) {
+            if sender.send(request).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut server = Server {
+        seq: 0,
+        requests,
+        pending: VecDeque::new(),
+        output: Box::new(output),
+        debugger: Debugger::build(),
+        program: None,
+        source_breakpoints: HashSet::new(),
+        function_breakpoints: HashSet::new(),
+        instruction_breakpoints: HashSet::new(),
+    };
+
+    while let Some(request) = server.next_request() {
+        if !server.handle(&request) {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use std::os::unix::net::UnixStream;
+
+    struct Client {
+        stream: UnixStream,
+        replies: BufReader<UnixStream>,
+        seq: i64,
+    }
+
+    impl Client {
+        fn request(&mut self, command: &str, arguments: Value) {
+            self.seq += 1;
+            let body = json!({ "seq": self.seq, "type": "request", "command": command, "arguments": arguments });
+            let body = body.to_string();
+            write!(self.stream, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        }
+
+        /// The next message that isn't output, which should be `kind`.
+        fn expect(&mut self, kind: &str, name: &str) -> Value {
+            loop {
+                let message = read_message(&mut self.replies).expect("The server hung up");
+                if message["event"] == "output" {
+                    continue;
+                }
+
+                let key = if kind == "event" { "event" } else { "command" };
+                assert_eq!((message["type"].as_str(), message[key].as_str()), (Some(kind), Some(name)), "{}", message);
+                return message;
+            }
+        }
+    }
+
+    #[test]
+    fn pauses_a_running_program() {
+        let directory = std::env::temp_dir();
+        let binary = directory.join(format!("dap-test-{}.bin", std::process::id()));
+        let listing = directory.join(format!("dap-test-{}.dis", std::process::id()));
+        build::write_binary(binary.to_str().unwrap(), &asm::assemble("spin: jmp spin").ok().unwrap());
+
+        let (stream, server) = UnixStream::pair().unwrap();
+        let input = server.try_clone().unwrap();
+        std::thread::spawn(move || session(input, server));
+        let mut client = Client {
+            replies: BufReader::new(stream.try_clone().unwrap()),
+            stream,
+            seq: 0,
+        };
+
+        client.request("initialize", json!({}));
+        client.expect("response", "initialize");
+        client.request("launch", json!({ "program": binary, "listing": listing }));
+        client.expect("response", "launch");
+        client.expect("event", "initialized");
+        client.request("configurationDone", json!({}));
+        client.expect("response", "configurationDone");
+
+        // The loop never stops by itself
+        client.request("pause", json!({}));
+        client.expect("response", "pause");
+        let stopped = client.expect("event", "stopped");
+        assert_eq!(stopped["body"]["reason"], "pause");
+
+        client.request("evaluate", json!({ "expression": "!x" }));
+        let evaluated = client.expect("response", "evaluate");
+        assert_eq!(evaluated["body"]["result"], "Usage: x <address> [count]\n");
+
+        client.request("stackTrace", json!({ "threadId": 1 }));
+        let trace = client.expect("response", "stackTrace");
+        assert_eq!(trace["body"]["stackFrames"][0]["instructionPointerReference"], "0x0000");
+
+        client.request("disconnect", json!({}));
+        client.expect("response", "disconnect");
+
+        std::fs::remove_file(binary).unwrap();
+        std::fs::remove_file(listing).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+//! Register data flow: which registers each instruction reads and writes, and liveness.
+
+use super::cfg::{Block, Cfg, Target};
+use super::disasm::{Analysis, Decoded};
+use super::opcode::Opcode;
+use std::collections::BTreeMap;
+
+/// A set of registers, one bit each.
+pub type Registers = u8;
+
+pub const ALL: Registers = 0xff;
+
+/// The registers `instruction` reads as operands.
+pub fn reads(instruction: &Decoded) -> Registers {
+    instruction.lift().map_or(0, |e| e.reads())
+}
+
+/// The registers `instruction` writes.
+pub fn writes(instruction: &Decoded) -> Registers {
+    instruction.lift().map_or(0, |e| e.writes())
+}
+
+pub fn contains(registers: Registers, r: usize) -> bool {
+    registers & (1 << r) != 0
+}
+
+/// Registers live at the start and end of each block, by block address.
+///
+/// Without knowing what callees and callers expect, every register is assumed to be read by
+/// a `Call`, at a `Ret` and wherever control leaves the function.
+pub fn liveness(analysis: &Analysis, cfg: &Cfg) -> BTreeMap<usize, (Registers, Registers)> {
+    let effect = |address: usize| {
+        let instruction = &analysis.code[&address];
+        match instruction.opcode {
+            Opcode::Call | Opcode::Ret => (ALL, 0),
+            _ => (reads(instruction), writes(instruction)),
+        }
+    };
+    let exit = |block: &Block, target: Target| match target {
+        Target::Exit if analysis.code[block.instructions.last().unwrap()].opcode == Opcode::Halt => 0,
+        _ => ALL,
+    };
+
+    solve(cfg, effect, exit)
+}
+
+/// Liveness given `effect`, the registers each instruction reads and definitely writes, and
+/// `exit`, the registers live when control leaves a block for anything but another block.
+pub fn solve(
+    cfg: &Cfg,
+    effect: impl Fn(usize) -> (Registers, Registers),
+    exit: impl Fn(&Block, Target) -> Registers,
+) -> BTreeMap<usize, (Registers, Registers)> {
+    // What each block reads before writing, and what it writes
+    let summaries: BTreeMap<usize, (Registers, Registers)> = cfg
+        .blocks
+        .values()
+        .map(|block| {
+            let (mut used, mut defined) = (0, 0);
+            for &address in &block.instructions {
+                let (read, written) = effect(address);
+                used |= read & !defined;
+                defined |= written;
+            }
+            (block.start, (used, defined))
+        })
+        .collect();
+
+    let mut live: BTreeMap<usize, (Registers, Registers)> = cfg.blocks.keys().map(|&b| (b, (0, 0))).collect();
+
+    loop {
+        let mut changed = false;
+
+        for block in cfg.blocks.values().rev() {
+            let out = block.successors.iter().fold(0, |out, (target, _)| {
+                out | match target {
+                    Target::Block(b) => live[b].0,
+                    _ => exit(block, *target),
+                }
+            });
+
+            let (used, defined) = summaries[&block.start];
+            let entry = (used | (out & !defined), out);
+
+            if live[&block.start] != entry {
+                live.insert(block.start, entry);
+                changed = true;
+            }
+        }
+
+        if !changed {
+            return live;
+        }
+    }
+}
+
+/// The registers live just after each instruction in `block`, given those live at its end.
+pub fn live_after(block: &Block, live_out: Registers, effect: impl Fn(usize) -> (Registers, Registers)) -> Vec<(usize, Registers)> {
+    let mut live = live_out;
+    let mut after: Vec<_> = block
+        .instructions
+        .iter()
+        .rev()
+        .map(|&address| {
+            let here = live;
+            let (read, written) = effect(address);
+            live = read | (live & !written);
+            (address, here)
+        })
+        .collect();
+
+    after.reverse();
+    after
+}
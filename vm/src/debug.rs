@@ -1,12 +1,29 @@
-use super::exec::{Instruction, State};
-use super::opcode::Opcode;
+use super::callgraph::CallGraph;
+use super::convention::{self, Signature};
+use super::disasm;
+use super::exec::{Instruction, State, Step};
+use super::hexview::HexView;
+use super::library::Library;
+use super::search::{self, Pattern};
+use super::stackdepth;
+use super::strings;
+use super::symbols;
+use super::taint::Shadow;
+use super::tui;
 use super::util;
-use std::collections::{HashMap, HashSet};
+use super::xref::Xrefs;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::Write;
 use std::iter::FromIterator;
+use std::time::{Duration, Instant};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Statistics {
-    calls: HashMap<u16, usize>,
+    pub calls: HashMap<u16, usize>,
+    /// Call counts per call site, then per target.
+    pub call_sites: HashMap<u16, HashMap<u16, usize>>,
+    /// Counts of jumps taken through a register, per site, then per target.
+    pub jumps: HashMap<u16, HashMap<u16, usize>>,
     instructions: usize,
 }
 
@@ -14,13 +31,46 @@ impl Statistics {
     pub fn new() -> Statistics {
         Statistics {
             calls: HashMap::new(),
+            call_sites: HashMap::new(),
+            jumps: HashMap::new(),
             instructions: 0
         }
     }
 
-    pub fn record_call(&mut self, n: u16) {
+    pub fn record_call(&mut self, site: u16, n: u16) {
         let entry = self.calls.entry(n).or_insert(0);
         *entry += 1;
+
+        let entry = self.call_sites.entry(site).or_default().entry(n).or_insert(0);
+        *entry += 1;
+    }
+
+    pub fn record_jump(&mut self, site: u16, n: u16) {
+        let entry = self.jumps.entry(site).or_default().entry(n).or_insert(0);
+        *entry += 1;
+    }
+
+    /// Writes the call counts out as a JSON profile that `load` can read back.
+    pub fn save(&self, filename: &str) {
+        let profile = serde_json::json!({
+            "calls": self.calls,
+            "call_sites": self.call_sites,
+            "jumps": self.jumps,
+        });
+
+        std::fs::write(filename, profile.to_string()).expect("Failed to write profile");
+    }
+
+    pub fn load(filename: &str) -> Statistics {
+        let profile = std::fs::read_to_string(filename).expect("Failed to read profile");
+        let profile: serde_json::Value = serde_json::from_str(&profile).expect("Invalid profile");
+
+        Statistics {
+            calls: serde_json::from_value(profile["calls"].clone()).unwrap_or_default(),
+            call_sites: serde_json::from_value(profile["call_sites"].clone()).unwrap_or_default(),
+            jumps: serde_json::from_value(profile["jumps"].clone()).unwrap_or_default(),
+            instructions: 0,
+        }
     }
 
     pub fn record_instruction(&mut self) {
@@ -33,8 +83,88 @@ pub struct Debugger {
     pub enabled: bool,
     pub labels: HashMap<usize, String>,
     pub stats: Statistics,
+    pub tui: bool,
+    pub recent: VecDeque<usize>,
+    pub hexview: HexView,
+    /// Routines to look for in memory, labelled wherever they're found.
+    pub library: Library,
+    /// The calling conventions `library` gives for the routines it found, by address.
+    pub declared: BTreeMap<usize, Signature>,
+    /// `State::writes` when memory was last scanned for `library`'s routines.
+    scanned: Option<usize>,
 }
 
+/// Argument `n` of a debugger command, if it's there and parses.
+fn argument<T: std::str::FromStr>(response: &[&str], n: usize) -> Option<T> {
+    response.get(n)?.parse().ok()
+}
+
+/// What the execution loop should do once a debugger command has been handled.
+pub enum Action {
+    Prompt,
+    Resume,
+    /// `ip` has moved, so carry on from there rather than executing the current instruction.
+    Jump,
+}
+
+/// How far `Debugger::resume` should run before handing control back.
+pub enum Resume {
+    Step,
+    Continue,
+    /// Runs any `Call` at `ip` to completion before stopping.
+    StepOver,
+    /// Runs until the current function returns.
+    StepOut,
+}
+
+/// Why `Debugger::resume` handed control back.
+pub enum Stop {
+    Step,
+    Breakpoint,
+    Halted,
+    InvalidOpcode,
+    Interrupted,
+    AwaitingInput,
+}
+
+// How many instructions `resume` runs between checks for an interrupt request.
+const INTERRUPT_INTERVAL: usize = 4096;
+
+// How often the full-screen UI is redrawn during a long run.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+const OPTIONS: [&str; 26] = [
+    "(s): Step",
+    "(m): Dump Memory",
+    "(b <addr>): Add breakpoint",
+    "(cstat): Clear statistics",
+    "(stat): Dump Statistics",
+    "(pstat <file>): Save call counts as a profile for `vm callgraph`",
+    "(mc <key>): Dump Memory (Coded)",
+    "(ip <value>): Set IP",
+    "(e): Dump Registers",
+    "(g <index> <value>): Set Register",
+    "(t): Dump Stack, split into each function's frame",
+    "(cs): Dump Call Stack, with each function's inferred signature",
+    "(c): Continue",
+    "(w <address> <value>): Write to memory",
+    "(l <count>): Log instructions",
+    "(r <address> <count>): Read from memory",
+    "(x <address> [count]): Hexdump memory",
+    "(xr / xw): Toggle hexdump radix (hex/dec) / width (8/16)",
+    "(xrefs <address>): List calls, jumps, reads and writes referring to an address",
+    "(find v|w|s|p|k <...>): Find a value, words, string, prefixed string or coded string (k <key> <text>)",
+    "(taint [on|off]): Track which input characters values come from and where output comes from / Show the registers' taint",
+    "(taint m <address> <count> / taint report): Show the taint of memory / List code comparing against input",
+    "(whence \"<text>\"): Show the code, call stack and string behind printed text (needs `taint on`)",
+    "(sigs [file]): List the routines the signature library matches, after loading it from a file",
+    "(tui): Toggle full-screen UI",
+    "(h): Help",
+];
+
+// Number of executed instructions kept around so the TUI can show what led up to `ip`.
+const RECENT_LENGTH: usize = 4;
+
 impl Debugger {
     pub fn build() -> Debugger {
         let breakpoints = vec![];
@@ -43,25 +173,27 @@ impl Debugger {
         // let breakpoints = vec![1841];
         // let breakpoints = vec![2950, 2952];
 
-        let labels = vec![
-            (1458, "Map fn impl. r0: string memloc, r1: mapping routine. ret: r1 = 0 if early-exit (routine returns 32767)"),
-            (1543, "Map fn. r0: string memloc, r1: mapping routine. ret: r0 = 32767 on succ, r0 = r2 otherwise"),
-            (1605, "Char matcher fn. r0: incoming char, r2: needle. ret: r1 = 23767 if a match is found."),
-            (1605, "Char matcher impl. r0: incoming char, r2: needle. ret: r1 = 23767 if a match is found."),
-            (2125, "Decoder logic. r0: encoded char, r1: key. ret: r0 = decoded char."),
-            (2826, "Main loop (?)")
-        ];
-
-        let labels = labels.into_iter().map(|(k, v)| (k as usize, v.to_owned()));
-
         Debugger {
-            breakpoints: HashSet::from_iter(breakpoints.into_iter()),
+            breakpoints: HashSet::from_iter(breakpoints),
             enabled: false,
-            labels: HashMap::from_iter(labels),
+            labels: symbols::labels(),
             stats: Statistics::new(),
+            tui: false,
+            recent: VecDeque::new(),
+            hexview: HexView::new(),
+            library: Library::builtin(),
+            declared: BTreeMap::new(),
+            scanned: None,
         }
     }
 
+    pub fn record_ip(&mut self, ip: usize) {
+        if self.recent.len() == RECENT_LENGTH {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(ip);
+    }
+
     pub fn check_for_breakpoints(&mut self, ip: usize) {
         if self.breakpoints.contains(&(ip as u16)) {
             self.enabled = true;
@@ -72,163 +204,549 @@ impl Debugger {
         self.enabled = true;
     }
 
-    pub fn maybe_present(&mut self, state: &mut State, instruction: &Instruction) {
+    /// Executes the instruction at `ip` without presenting the interactive prompt.
+    pub fn step(&mut self, state: &mut State) -> Stop {
+        let instruction = match state.instruction(state.ip) {
+            Some(instruction) => instruction,
+            None => return Stop::InvalidOpcode,
+        };
+
+        self.stats.record_instruction();
+        self.record_ip(state.ip);
+
+        match state.execute(instruction, self) {
+            Step::Running => Stop::Step,
+            Step::Halted => Stop::Halted,
+            Step::AwaitingInput => Stop::AwaitingInput,
+        }
+    }
+
+    /// Runs until a breakpoint is reached or execution stops for some other reason.
+    /// The instruction at `ip` is always executed, even if it has a breakpoint.
+    /// `interrupted` is polled every so often so a front-end can pause a long run.
+    pub fn resume(
+        &mut self,
+        state: &mut State,
+        resume: Resume,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> Stop {
+        let mut count = 0;
+        let depth = state.call_stack.len();
+        let mut redrawn = Instant::now();
+
+        loop {
+            let stop = self.step(state);
+
+            if let Stop::Step = stop {
+                let done = match resume {
+                    Resume::Step => true,
+                    Resume::Continue => false,
+                    Resume::StepOver => state.call_stack.len() <= depth,
+                    Resume::StepOut => state.call_stack.len() < depth,
+                };
+
+                if done {
+                    return Stop::Step;
+                }
+            } else {
+                return stop;
+            }
+
+            if self.breakpoints.contains(&(state.ip as u16)) {
+                return Stop::Breakpoint;
+            }
+
+            count += 1;
+            if count % INTERRUPT_INTERVAL == 0 {
+                if self.tui && redrawn.elapsed() >= REDRAW_INTERVAL {
+                    tui::render(self, state, "");
+                    redrawn = Instant::now();
+                }
+
+                if interrupted() {
+                    return Stop::Interrupted;
+                }
+            }
+        }
+    }
+
+    /// Presents the prompt if the debugger is enabled. Returns whether a command moved `ip`,
+    /// in which case `instruction` shouldn't be executed.
+    pub fn maybe_present(&mut self, state: &mut State, instruction: &Instruction) -> bool {
         if self.enabled {
-            let original_ip = state.ip.clone();
-            state.ip -= instruction.args.len() + 1;
+            // Memory may have changed since the last stop, e.g. by decrypting itself
+            if self.scanned != Some(state.writes) {
+                self.declared = self.library.apply(&state.memory(), &mut self.labels);
+                self.scanned = Some(state.writes);
+            }
+
+            let mut output = String::new();
 
             loop {
-                eprintln!(
-                    "\nCurrent Instruction: {}: {}",
-                    state.ip,
-                    instruction.disassemble(state.ip, &self.labels)
-                );
-
-                let options = [
-                    "  (s): Step",
-                    "(m): Dump Memory",
-                    "(b <addr>): Add breakpoint",
-                    "(cstat): Clear statistics",
-                    "(stat): Dump Statistics",
-                    "(mc <key>): Dump Memory (Coded)",
-                    "(ip <value>): Set IP",
-                    "(e): Dump Registers",
-                    "(g <index> <value>): Set Register",
-                    "(t): Dump Stack",
-                    "(cs): Dump Call Stack",
-                    "(c): Continue",
-                    "(w <address> <value>): Write to memory",
-                    "(l <count>): Log instructions",
-                    "(r <address> <count>): Read from memory",
-                ]
-                .join("\n  ");
-
-                eprint!("{}\nMake a choice: ", options);
+                if self.tui {
+                    tui::render(self, state, &output);
+                } else {
+                    eprintln!(
+                        "\nCurrent Instruction: {}: {}",
+                        state.ip,
+                        instruction.disassemble(state.ip, &self.labels, &state.registers)
+                    );
+                }
+
+                eprint!("Make a choice (h for help): ");
 
                 let mut response = String::new();
-                std::io::stdin().read_line(&mut response).unwrap();
+                if std::io::stdin().read_line(&mut response).unwrap() == 0 {
+                    // Nothing more will be typed
+                    std::process::exit(0);
+                }
                 let response = response.trim().split_ascii_whitespace().collect::<Vec<_>>();
 
-                match response[0] {
-                    "s" => {
-                        break;
-                    }
-                    "b" => {
-                        let address = response[1].parse::<u16>().unwrap();
-                        self.breakpoints.insert(address);
-                    }
-                    "cstat" => {
-                        self.stats = Statistics::new();
-                    }
-                    "stat" => {
-                        eprintln!("{:?}", self.stats);
-                    }
-                    "ip" => {
-                        let address = response[1].parse::<usize>().unwrap();
-                        state.ip = address;
-                        return;
+                output.clear();
+                let action = self.run_command(state, &response, &mut output);
+
+                if !self.tui {
+                    eprint!("{}", output);
+                }
+
+                match action {
+                    Action::Prompt => (),
+                    Action::Resume => break,
+                    Action::Jump => return true,
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Follows control flow through current memory, from the entry point, `ip`, every
+    /// return address on the call stack and the routines passed to the string mappers.
+    fn analyse(&self, state: &State) -> disasm::Analysis {
+        let returns = state.call_stack.iter().map(|&site| site as usize + 2);
+        let entries: Vec<_> = vec![0, state.ip].into_iter().chain(returns).collect();
+
+        let memory = state.memory();
+        let routines = strings::routines(&disasm::recursive_descent(&memory, &entries), &self.labels);
+        disasm::recursive_descent(&memory, &entries.into_iter().chain(routines).collect::<Vec<_>>())
+    }
+
+    fn taint(&self, state: &mut State, args: &[&str], out: &mut String) {
+        match args {
+            ["on"] => {
+                state.shadow = Some(Shadow::new());
+                writeln!(out, "Tracking input and output from here on").unwrap();
+                return;
+            }
+            ["off"] => {
+                state.shadow = None;
+                return;
+            }
+            _ => {}
+        }
+
+        let shadow = match &state.shadow {
+            Some(shadow) => shadow,
+            None => {
+                writeln!(out, "Taint tracking is off; turn it on with `taint on`").unwrap();
+                return;
+            }
+        };
+
+        match args {
+            [] => {
+                for r in 0..8 {
+                    let taint = shadow.register(r);
+                    if !taint.is_clean() {
+                        writeln!(out, "r{}: {}", r, taint.describe(&shadow.text)).unwrap();
                     }
-                    "e" => {
-                        eprintln!(
-                            "{:?}",
-                            state.registers.iter().enumerate().collect::<Vec<_>>()
-                        );
+                }
+                writeln!(out, "{} words of memory tainted", shadow.tainted_words()).unwrap();
+            }
+            ["m", _, _] => match (argument::<u16>(args, 1), argument::<u16>(args, 2)) {
+                (Some(address), Some(count)) if address <= 32767 => {
+                    for a in address..address.saturating_add(count).min(32768) {
+                        if let Some(taint) = shadow.memory(a) {
+                            writeln!(out, "{}: {}", a, taint.describe(&shadow.text)).unwrap();
+                        }
                     }
-                    "cs" => {
-                        let stack = state
-                            .call_stack
-                            .iter()
-                            .map(|v| v.to_string())
-                            .collect::<Vec<_>>();
-                        eprintln!("{}", stack.join(" "));
+                }
+                _ => writeln!(out, "Usage: taint m <address> <count>, with the address 0-32767").unwrap(),
+            },
+            ["report"] => {
+                for line in shadow.report(&state.memory(), &self.labels) {
+                    writeln!(out, "{}", line).unwrap();
+                }
+            }
+            _ => writeln!(out, "Usage: taint [on | off | m <address> <count> | report]").unwrap(),
+        }
+    }
+
+    fn whence(&self, state: &State, text: &str, out: &mut String) {
+        let shadow = match &state.shadow {
+            Some(shadow) => shadow,
+            None => {
+                writeln!(out, "Output isn't being traced; turn it on with `taint on`").unwrap();
+                return;
+            }
+        };
+
+        let text = text.strip_prefix('"').and_then(|t| t.strip_suffix('"')).unwrap_or(text);
+        let memory = state.memory();
+        let table = strings::scan(&memory, &strings::keys(&self.analyse(state), &self.labels));
+
+        match shadow.whence(text, &table, &self.labels) {
+            Some(lines) => {
+                for line in lines {
+                    writeln!(out, "{}", line).unwrap();
+                }
+            }
+            None => writeln!(out, "{:?} hasn't been printed since tracing started", text).unwrap(),
+        }
+    }
+
+    /// One line per frame, innermost first: where it is and the signature of the function
+    /// it's in, as inferred from current memory.
+    fn backtrace(&self, state: &State) -> Vec<String> {
+        let analysis = self.analyse(state);
+        let graph = CallGraph::build(&analysis, &[0], &self.labels, None);
+        let mut signatures = convention::infer(&analysis, &graph);
+        signatures.extend(&self.declared);
+
+        let pcs = std::iter::once(state.ip).chain(state.call_stack.iter().rev().map(|&s| s as usize));
+        pcs.enumerate()
+            .map(|(i, pc)| match graph.containing(pc) {
+                Some(f) => format!("#{:<2} {:5} in {}{}", i, pc, f.name, signatures[&f.entry]),
+                None => format!("#{:<2} {:5}", i, pc),
+            })
+            .collect()
+    }
+
+    /// The stack split into frames, innermost first, using each function's static stack
+    /// depth to say which words it pushed and where its return address should be. Also
+    /// returns the first frame whose return address isn't where `call_stack` says, since
+    /// from there on `call_stack` can't be trusted.
+    fn stack_layout(&self, state: &State) -> (Vec<String>, Option<usize>) {
+        let analysis = self.analyse(state);
+        let graph = CallGraph::resolved(&analysis, &[0], &self.labels, None);
+        let depths = stackdepth::analyse(&analysis, &graph);
+
+        let mut lines = Vec::new();
+        let mut distrust = None;
+        let mut top = state.stack.len();
+        let sites: Vec<_> = state.call_stack.iter().rev().map(|&s| s as usize).collect();
+        let pcs = std::iter::once(state.ip).chain(sites.iter().copied());
+
+        for (i, pc) in pcs.enumerate() {
+            // Where functions share code they can disagree on the depth, so prefer one that
+            // puts the return address where it should be
+            let mut functions: Vec<_> = graph.functions.values().filter(|f| f.instructions.contains(&pc)).collect();
+            functions.sort_by_key(|f| std::cmp::Reverse((f.entry <= pc, f.entry)));
+            let candidates: Vec<usize> = functions
+                .iter()
+                .filter_map(|f| depths[&f.entry].at.get(&pc))
+                .filter(|&&d| d >= 0 && d as usize <= top)
+                .map(|&d| d as usize)
+                .collect();
+            let returns_to_site = |d: &&usize| match (sites.get(i), top.checked_sub(*d + 1)) {
+                (Some(site), Some(t)) => state.stack[t] as usize == site + 2,
+                _ => false,
+            };
+
+            let depth = match candidates.iter().find(returns_to_site).or(candidates.first()) {
+                Some(&depth) => depth,
+                None => {
+                    lines.push(format!("#{:<2} {:5}: depth unknown", i, pc));
+                    break;
+                }
+            };
+
+            let words: Vec<_> = state.stack[top - depth..top].iter().rev().map(|w| w.to_string()).collect();
+            lines.push(format!("#{:<2} {}: {} word(s) {}", i, util::describe_address(pc, &self.labels), depth, words.join(" ")));
+            top -= depth;
+
+            let site = match sites.get(i) {
+                Some(&site) => site,
+                None => break,
+            };
+            match top.checked_sub(1).map(|t| state.stack[t]) {
+                Some(word) if word as usize == site + 2 => {
+                    lines.push(format!("    return address {}", word));
+                    top -= 1;
+                }
+                found => {
+                    let found = found.map(|w| w.to_string()).unwrap_or_else(|| "nothing".to_owned());
+                    lines.push(format!("    expected return address {} for the call at {}, found {}", site + 2, site, found));
+                    distrust = Some(i + 1);
+                    break;
+                }
+            }
+        }
+
+        if top > 0 && distrust.is_none() {
+            lines.push(format!("{} word(s) below the outermost frame", top));
+        }
+
+        (lines, distrust)
+    }
+
+    /// Runs a single debugger command, writing anything it prints to `out`.
+    /// `state.ip` is expected to point at the current instruction.
+    pub fn run_command(&mut self, state: &mut State, response: &[&str], out: &mut String) -> Action {
+        if response.is_empty() {
+            return Action::Prompt;
+        }
+
+        match response[0] {
+            "s" => {
+                return Action::Resume;
+            }
+            "b" => match argument(response, 1) {
+                Some(address) => {
+                    self.breakpoints.insert(address);
+                }
+                None => writeln!(out, "Usage: b <address>").unwrap(),
+            },
+            "cstat" => {
+                self.stats = Statistics::new();
+            }
+            "stat" => {
+                writeln!(out, "{:?}", self.stats).unwrap();
+            }
+            "pstat" => match response.get(1) {
+                Some(filename) => {
+                    self.stats.save(filename);
+                    writeln!(out, "Saved profile to {}", filename).unwrap();
+                }
+                None => writeln!(out, "Usage: pstat <file>").unwrap(),
+            },
+            "ip" => match argument(response, 1) {
+                Some(address) => {
+                    state.ip = address;
+                    return Action::Jump;
+                }
+                None => writeln!(out, "Usage: ip <value>").unwrap(),
+            },
+            "e" => {
+                writeln!(
+                    out,
+                    "{:?}",
+                    state.registers.iter().enumerate().collect::<Vec<_>>()
+                )
+                .unwrap();
+            }
+            "cs" => {
+                for line in self.backtrace(state) {
+                    writeln!(out, "{}", line).unwrap();
+                }
+                if let (_, Some(frame)) = self.stack_layout(state) {
+                    writeln!(out, "The stack doesn't match the call stack from frame #{} on; see `t`", frame).unwrap();
+                }
+            }
+            "w" => match (argument(response, 1), argument(response, 2)) {
+                (Some(address), Some(value)) => state.write_mem(address, value),
+                _ => writeln!(out, "Usage: w <address> <value>").unwrap(),
+            },
+            "g" => match (argument::<usize>(response, 1), argument(response, 2)) {
+                (Some(index @ 0..=7), Some(value)) => state.registers[index] = value,
+                _ => writeln!(out, "Usage: g <index> <value>, with the index 0-7").unwrap(),
+            },
+            "r" => {
+                let (address, count): (u16, u16) = match (argument(response, 1), argument(response, 2)) {
+                    (Some(address), Some(count)) => (address, count),
+                    _ => {
+                        writeln!(out, "Usage: r <address> <count>").unwrap();
+                        return Action::Prompt;
                     }
-                    "w" => {
-                        let address = response[1].parse::<u16>().unwrap();
-                        let value = response[2].parse::<u16>().unwrap();
-                        state.instructions.insert(address, value);
+                };
+
+                for i in address..address.saturating_add(count) {
+                    if let Some(instruction) = state.instructions.get(&i) {
+                        writeln!(
+                            out,
+                            "{}: {} / {}",
+                            i,
+                            instruction,
+                            util::maybe_to_ascii(*instruction)
+                        )
+                        .unwrap();
+                    } else {
+                        writeln!(out, "{}: <BLANK>", i).unwrap();
                     }
-                    "g" => {
-                        let address = response[1].parse::<usize>().unwrap();
-                        let value = response[2].parse::<u16>().unwrap();
-                        state.registers.insert(address, value);
+                }
+            }
+            "x" => {
+                let (address, count) = match (argument(response, 1), response.get(2)) {
+                    (Some(address), None) => (address, 64),
+                    (Some(address), Some(count)) if count.parse::<usize>().is_ok() => (address, count.parse().unwrap()),
+                    _ => {
+                        writeln!(out, "Usage: x <address> [count]").unwrap();
+                        return Action::Prompt;
                     }
-                    "r" => {
-                        let address = response[1].parse::<u16>().unwrap();
-                        let count = response[2].parse::<u16>().unwrap();
-
-                        for i in address..address + count {
-                            if let Some(instruction) = state.instructions.get(&i) {
-                                eprintln!(
-                                    "{}: {} / {}",
-                                    i,
-                                    instruction,
-                                    util::maybe_to_ascii(*instruction)
-                                );
-                            } else {
-                                eprintln!("{}: <BLANK>", i);
-                            }
-                        }
+                };
+
+                // Escape codes would end up as garbage in the TUI's panes
+                let mut hexview = self.hexview.clone();
+                hexview.color = !self.tui;
+
+                for line in hexview.render(&state.memory(), address, count, &self.labels) {
+                    writeln!(out, "{}", line).unwrap();
+                }
+            }
+            "xrefs" => {
+                let address = match argument(response, 1) {
+                    Some(address) => address,
+                    None => {
+                        writeln!(out, "Usage: xrefs <address>").unwrap();
+                        return Action::Prompt;
                     }
-                    "l" => {
-                        let original_ip = state.ip.clone();
-                        let count = if response.len() == 2 {
-                            response[1].parse::<u16>().unwrap()
-                        } else {
-                            5
-                        };
-                        let count = count as usize;
-
-                        for _ in 0..count {
-                            let ip = state.ip.clone();
-                            let opcode = Opcode::from(state.instructions[&(state.ip as u16)]);
-                            let instruction = Instruction::build(opcode, state);
-                            eprintln!(
-                                "          {}: {}",
-                                ip,
-                                instruction.disassemble(ip, &self.labels)
-                            );
+                };
+                let analysis = self.analyse(state);
+                let mut xrefs = Xrefs::build(&analysis);
+                xrefs.add_indirect(&analysis, &CallGraph::resolved(&analysis, &[0], &self.labels, None).targets);
+
+                for xref in xrefs.to(address) {
+                    writeln!(out, "{} from {}", xref.kind, util::describe_address(xref.from, &self.labels)).unwrap();
+                }
+
+                writeln!(out, "{} xref(s)", xrefs.to(address).len()).unwrap();
+            }
+            "xr" => {
+                self.hexview.toggle_radix();
+            }
+            "xw" => {
+                self.hexview.toggle_width();
+            }
+            "l" => {
+                let count = match response.get(1) {
+                    Some(count) => match count.parse::<u16>() {
+                        Ok(count) => count,
+                        Err(_) => {
+                            writeln!(out, "Usage: l <count>").unwrap();
+                            return Action::Prompt;
                         }
+                    },
+                    None => 5,
+                };
+                let mut ip = state.ip;
 
-                        state.ip = original_ip;
-                    }
-                    "mc" => {
-                        let key = response[1].parse::<u16>().unwrap();
-                        let mem: Vec<_> = state.instructions.iter().collect();
-                        let mut mem = mem.clone();
-                        mem.sort_by_key(|(&i, _)| i);
-                        let mem = mem
-                            .iter()
-                            .map(|(_, &v)| util::maybe_to_ascii_coded(v, key))
-                            .collect::<String>();
-                        eprintln!("{}", mem);
-                    }
-                    "m" => {
-                        let mem: Vec<_> = state.instructions.iter().collect();
-                        let mut mem = mem.clone();
-                        mem.sort_by_key(|(&i, _)| i);
-                        let mem = mem
-                            .iter()
-                            .map(|(_, &v)| util::maybe_to_ascii(v))
-                            .collect::<String>();
-                        eprintln!("{}", mem);
+                for _ in 0..count {
+                    let instruction = match state.instruction(ip) {
+                        Some(instruction) => instruction,
+                        None => break,
+                    };
+                    writeln!(
+                        out,
+                        "          {}: {}",
+                        ip,
+                        instruction.disassemble(ip, &self.labels, &state.registers)
+                    )
+                    .unwrap();
+                    ip += instruction.size();
+                }
+            }
+            "mc" => {
+                let key = match argument(response, 1) {
+                    Some(key) => key,
+                    None => {
+                        writeln!(out, "Usage: mc <key>").unwrap();
+                        return Action::Prompt;
                     }
-                    "t" => {
-                        let stack = state
-                            .stack
-                            .iter()
-                            .map(|v| v.to_string())
-                            .collect::<Vec<_>>();
-                        eprintln!("{}", stack.join(" "));
+                };
+                let mem: Vec<_> = state.instructions.iter().collect();
+                let mut mem = mem.clone();
+                mem.sort_by_key(|(&i, _)| i);
+                let mem = mem
+                    .iter()
+                    .map(|(_, &v)| util::maybe_to_ascii_coded(v, key))
+                    .collect::<String>();
+                writeln!(out, "{}", mem).unwrap();
+            }
+            "m" => {
+                let mem: Vec<_> = state.instructions.iter().collect();
+                let mut mem = mem.clone();
+                mem.sort_by_key(|(&i, _)| i);
+                let mem = mem
+                    .iter()
+                    .map(|(_, &v)| util::maybe_to_ascii(v))
+                    .collect::<String>();
+                writeln!(out, "{}", mem).unwrap();
+            }
+            "t" => {
+                let stack = state
+                    .stack
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect::<Vec<_>>();
+                writeln!(out, "{}", stack.join(" ")).unwrap();
+
+                for line in self.stack_layout(state).0 {
+                    writeln!(out, "{}", line).unwrap();
+                }
+            }
+            "c" if self.tui => {
+                // Run here rather than in `run_loop`, so `resume` keeps the screen up to date
+                if let Stop::Halted = self.resume(state, Resume::Continue, &mut || false) {
+                    // Go back to the `halt` and let `run_loop` finish normally
+                    state.ip = self.recent.back().copied().unwrap_or(state.ip);
+                    self.enabled = false;
+                }
+                return Action::Jump;
+            }
+            "c" => {
+                self.enabled = false;
+                return Action::Resume;
+            }
+            "find" => match Pattern::parse(&response[1..]) {
+                Some(pattern) => {
+                    let matches = search::find(&state.memory(), &pattern);
+
+                    for address in &matches {
+                        writeln!(out, "{}", util::describe_address(*address, &self.labels)).unwrap();
                     }
-                    "c" => {
-                        self.enabled = false;
-                        break;
+
+                    writeln!(out, "{} match(es)", matches.len()).unwrap();
+                }
+                None => {
+                    writeln!(out, "Usage: find v <value> | w <word>... | s <text> | p <text> | k <key> <text>").unwrap();
+                }
+            },
+            "taint" => self.taint(state, &response[1..], out),
+            "sigs" => {
+                if let Some(filename) = response.get(1) {
+                    match Library::load(filename) {
+                        Ok(library) => self.library = library,
+                        Err(error) => {
+                            writeln!(out, "{}", error).unwrap();
+                            return Action::Prompt;
+                        }
                     }
-                    _ => panic!("Invalid response"),
-                };
+                }
+                let memory = state.memory();
+                self.declared = self.library.apply(&memory, &mut self.labels);
+                self.scanned = Some(state.writes);
+
+                for (address, routine) in self.library.scan(&memory) {
+                    writeln!(out, "{:5} {}", address, routine.label).unwrap();
+                }
             }
+            "whence" => self.whence(state, &response[1..].join(" "), out),
+            "tui" => {
+                self.tui = !self.tui;
+                state.set_scrollback(self.tui.then_some(tui::SCROLLBACK));
+                tui::forget_size();
+                if !self.tui {
+                    eprint!("{}", tui::RESET);
+                }
+            }
+            "h" => {
+                writeln!(out, "{}", OPTIONS.join("\n")).unwrap();
+            }
+            _ => {
+                writeln!(out, "Invalid response").unwrap();
+            }
+        };
 
-            state.ip = original_ip;
-        }
+        Action::Prompt
     }
 }
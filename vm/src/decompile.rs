@@ -0,0 +1,771 @@
+//! A decompiler from a function's control flow graph to structured pseudocode.
+//!
+//! Register arithmetic within a block is folded into expressions, so only the assignments
+//! that are still live when the block ends (or that a call might read) are written out.
+//! Loops come from back edges in the dominator tree and `if`/`else` joins from
+//! post-dominators; anything that doesn't fit becomes a `goto`.
+
+use super::callgraph::Function;
+use super::cfg::{Cfg, Target};
+use super::convention::{self, Signature};
+use super::dataflow;
+use super::disasm::{Analysis, Decoded};
+use super::ir::{self, Effect};
+use super::opcode::{Opcode, Operand};
+use super::symbols;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Add,
+    Mult,
+    Mod,
+    Eq,
+    Gt,
+    And,
+    Or,
+    Xor,
+}
+
+impl Op {
+    fn of(opcode: Opcode) -> Op {
+        match opcode {
+            Opcode::Add => Op::Add,
+            Opcode::Mult => Op::Mult,
+            Opcode::Mod => Op::Mod,
+            Opcode::Eq => Op::Eq,
+            Opcode::Gt => Op::Gt,
+            Opcode::And => Op::And,
+            Opcode::Or => Op::Or,
+            _ => unreachable!("{} isn't a binary operation", opcode),
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Op::Add => "+",
+            Op::Mult => "*",
+            Op::Mod => "%",
+            Op::Eq => "==",
+            Op::Gt => ">",
+            Op::And => "&",
+            Op::Or => "|",
+            Op::Xor => "^",
+        }
+    }
+
+    /// Binding strength, as in C.
+    fn precedence(self) -> u8 {
+        match self {
+            Op::Mult | Op::Mod => 10,
+            Op::Add => 9,
+            Op::Gt => 7,
+            Op::Eq => 6,
+            Op::And => 5,
+            Op::Xor => 4,
+            Op::Or => 3,
+        }
+    }
+
+    fn apply(self, a: u16, b: u16) -> Option<u16> {
+        let opcode = match self {
+            Op::Add => Opcode::Add,
+            Op::Mult => Opcode::Mult,
+            Op::Mod => Opcode::Mod,
+            Op::Eq => Opcode::Eq,
+            Op::Gt => Opcode::Gt,
+            Op::And => Opcode::And,
+            Op::Or => Opcode::Or,
+            // There's no xor instruction; it's only ever folded from this
+            Op::Xor => {
+                let both = ir::not(ir::apply(Opcode::And, a, b)?);
+                return ir::apply(Opcode::And, ir::apply(Opcode::Or, a, b)?, both);
+            }
+        };
+
+        ir::apply(opcode, a, b)
+    }
+}
+
+#[derive(Clone, PartialEq)]
+enum Expr {
+    Const(u16),
+    Reg(usize),
+    /// A copy of a register taken to break a cycle of assignments.
+    Temp(usize),
+    Mem(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn binary(op: Op, a: Expr, b: Expr) -> Expr {
+        if let (Expr::Const(x), Expr::Const(y)) = (&a, &b) {
+            if let Some(value) = op.apply(*x, *y) {
+                return Expr::Const(value);
+            }
+        }
+
+        // `(a | b) & ~(a & b)` is how the program spells `a ^ b`
+        if op == Op::And {
+            for (or, not) in [(&a, &b), (&b, &a)] {
+                if let (Expr::Binary(Op::Or, x, y), Expr::Not(inner)) = (or, not) {
+                    if let Expr::Binary(Op::And, p, q) = &**inner {
+                        if (x == p && y == q) || (x == q && y == p) {
+                            return Expr::Binary(Op::Xor, x.clone(), y.clone());
+                        }
+                    }
+                }
+            }
+        }
+
+        Expr::Binary(op, Box::new(a), Box::new(b))
+    }
+
+    fn not(a: Expr) -> Expr {
+        match a {
+            Expr::Const(c) => Expr::Const(ir::not(c)),
+            Expr::Not(inner) => *inner,
+            a => Expr::Not(Box::new(a)),
+        }
+    }
+
+    fn uses(&self, r: usize) -> bool {
+        match self {
+            Expr::Reg(reg) => *reg == r,
+            Expr::Const(_) | Expr::Temp(_) => false,
+            Expr::Mem(a) | Expr::Not(a) => a.uses(r),
+            Expr::Binary(_, a, b) => a.uses(r) || b.uses(r),
+        }
+    }
+
+    fn reads_memory(&self) -> bool {
+        match self {
+            Expr::Mem(_) => true,
+            Expr::Const(_) | Expr::Reg(_) | Expr::Temp(_) => false,
+            Expr::Not(a) => a.reads_memory(),
+            Expr::Binary(_, a, b) => a.reads_memory() || b.reads_memory(),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Expr::Const(_) | Expr::Reg(_) | Expr::Temp(_) => 1,
+            Expr::Mem(a) | Expr::Not(a) => 1 + a.size(),
+            Expr::Binary(_, a, b) => 1 + a.size() + b.size(),
+        }
+    }
+
+    /// Replaces every occurrence of `from` with `to`.
+    fn replace(&mut self, from: &Expr, to: &Expr) {
+        if self == from {
+            *self = to.clone();
+            return;
+        }
+
+        match self {
+            Expr::Mem(a) | Expr::Not(a) => a.replace(from, to),
+            Expr::Binary(_, a, b) => {
+                a.replace(from, to);
+                b.replace(from, to);
+            }
+            _ => {}
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Binary(op, _, _) => op.precedence(),
+            _ => 20,
+        }
+    }
+
+    /// Formats the expression as a condition, negated if asked for.
+    fn condition(&self, negate: bool) -> String {
+        match (self, negate) {
+            (_, false) => self.to_string(),
+            (Expr::Binary(Op::Eq, a, b), true) => format!("{} != {}", a, b),
+            (Expr::Binary(Op::Gt, a, b), true) => format!("{} <= {}", a, b),
+            (e, true) if e.precedence() < 20 => format!("!({})", e),
+            (e, true) => format!("!{}", e),
+        }
+    }
+}
+
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Expr::Const(c) => write!(f, "{}", c),
+            Expr::Reg(r) => write!(f, "r{}", r),
+            Expr::Temp(t) => write!(f, "t{}", t),
+            Expr::Mem(a) => write!(f, "mem[{}]", a),
+            Expr::Not(a) if a.precedence() < 20 => write!(f, "~({})", a),
+            Expr::Not(a) => write!(f, "~{}", a),
+            Expr::Binary(op, a, b) => {
+                let side = |e: &Expr, right: bool| {
+                    let wrap = e.precedence() < op.precedence() || (right && e.precedence() == op.precedence());
+                    if wrap { format!("({})", e) } else { e.to_string() }
+                };
+
+                // Adding a large constant is how the program subtracts
+                match &**b {
+                    Expr::Const(c) if *op == Op::Add && *c >= 16384 => {
+                        write!(f, "{} - {}", side(a, false), 32768 - *c as u32)
+                    }
+                    _ => write!(f, "{} {} {}", side(a, false), op.symbol(), side(b, true)),
+                }
+            }
+        }
+    }
+}
+
+/// A pseudocode-friendly version of a function name, e.g. `map_fn_impl`.
+fn identifier(address: usize, labels: &HashMap<usize, String>) -> String {
+    let name = symbols::function_name(address, labels);
+    let words: Vec<_> = name.split(|c: char| !c.is_ascii_alphanumeric()).filter(|w| !w.is_empty()).collect();
+    words.join("_").to_lowercase()
+}
+
+struct Loop {
+    body: BTreeSet<usize>,
+    /// Where control goes once the loop is done, if it ever is.
+    follow: Option<usize>,
+}
+
+struct Decompiler<'a> {
+    analysis: &'a Analysis,
+    cfg: &'a Cfg,
+    labels: &'a HashMap<usize, String>,
+    /// Every function's signature, for the arguments of calls to it.
+    signatures: &'a BTreeMap<usize, Signature>,
+    live: BTreeMap<usize, (dataflow::Registers, dataflow::Registers)>,
+    loops: BTreeMap<usize, Loop>,
+    /// Immediate post-dominator of each block, if it has one within the function.
+    joins: BTreeMap<usize, usize>,
+    lines: Vec<String>,
+    emitted: BTreeSet<usize>,
+    /// Blocks reached by a `goto`, which need a label.
+    gotos: BTreeSet<usize>,
+    temps: usize,
+}
+
+fn successors(block: &super::cfg::Block) -> impl Iterator<Item = usize> + '_ {
+    block.successors.iter().filter_map(|(target, _)| match target {
+        Target::Block(b) => Some(*b),
+        _ => None,
+    })
+}
+
+/// Dominator sets, found by iterating to a fixed point over `nodes`.
+fn dominators(nodes: &[usize], entry: usize, predecessors: &BTreeMap<usize, Vec<usize>>) -> BTreeMap<usize, BTreeSet<usize>> {
+    let all: BTreeSet<usize> = nodes.iter().copied().collect();
+    let mut dominators: BTreeMap<usize, BTreeSet<usize>> = nodes.iter().map(|&n| (n, all.clone())).collect();
+    dominators.insert(entry, BTreeSet::from([entry]));
+
+    loop {
+        let mut changed = false;
+
+        for &node in nodes.iter().filter(|&&n| n != entry) {
+            let mut set = predecessors[&node]
+                .iter()
+                .map(|p| dominators[p].clone())
+                .reduce(|a, b| a.intersection(&b).copied().collect())
+                .unwrap_or_default();
+            set.insert(node);
+
+            if dominators[&node] != set {
+                dominators.insert(node, set);
+                changed = true;
+            }
+        }
+
+        if !changed {
+            return dominators;
+        }
+    }
+}
+
+impl<'a> Decompiler<'a> {
+    fn new(
+        analysis: &'a Analysis,
+        cfg: &'a Cfg,
+        labels: &'a HashMap<usize, String>,
+        signatures: &'a BTreeMap<usize, Signature>,
+    ) -> Decompiler<'a> {
+        let nodes: Vec<usize> = cfg.blocks.keys().copied().collect();
+
+        let mut predecessors: BTreeMap<usize, Vec<usize>> = nodes.iter().map(|&n| (n, Vec::new())).collect();
+        for block in cfg.blocks.values() {
+            for s in successors(block) {
+                predecessors.get_mut(&s).unwrap().push(block.start);
+            }
+        }
+
+        // Post-dominators are dominators of the reversed graph, from a virtual exit node
+        const EXIT: usize = usize::MAX;
+        let mut reversed: BTreeMap<usize, Vec<usize>> = nodes.iter().map(|&n| (n, Vec::new())).collect();
+        reversed.insert(EXIT, Vec::new());
+        for block in cfg.blocks.values() {
+            let leaves = block.successors.iter().any(|(t, _)| !matches!(t, Target::Block(_)));
+            for s in successors(block) {
+                reversed.get_mut(&block.start).unwrap().push(s);
+            }
+            if leaves {
+                reversed.get_mut(&block.start).unwrap().push(EXIT);
+            }
+        }
+        let mut reverse_nodes = nodes.clone();
+        reverse_nodes.push(EXIT);
+        let post = dominators(&reverse_nodes, EXIT, &reversed);
+
+        let joins: BTreeMap<usize, usize> = nodes
+            .iter()
+            .filter_map(|&n| {
+                let strict: BTreeSet<usize> = post[&n].iter().copied().filter(|&d| d != n).collect();
+                let join = strict.iter().copied().find(|d| post.get(d) == Some(&strict))?;
+                Some((n, join)).filter(|&(_, j)| j != EXIT)
+            })
+            .collect();
+
+        let dominators = dominators(&nodes, cfg.entry, &predecessors);
+        let mut loops: BTreeMap<usize, Loop> = BTreeMap::new();
+
+        for block in cfg.blocks.values() {
+            for header in successors(block).filter(|h| dominators[&block.start].contains(h)) {
+                // The natural loop: everything that reaches the back edge without passing the header
+                let body = &mut loops.entry(header).or_insert_with(|| Loop {
+                    body: BTreeSet::from([header]),
+                    follow: None,
+                }).body;
+
+                let mut pending = vec![block.start];
+                while let Some(node) = pending.pop() {
+                    if body.insert(node) {
+                        pending.extend(predecessors[&node].iter().copied());
+                    }
+                }
+            }
+        }
+
+        for (header, l) in loops.iter_mut() {
+            let exits: BTreeSet<usize> = l
+                .body
+                .iter()
+                .flat_map(|b| successors(&cfg.blocks[b]))
+                .filter(|s| !l.body.contains(s))
+                .collect();
+
+            l.follow = match joins.get(header) {
+                Some(join) if exits.contains(join) => Some(*join),
+                _ => exits.iter().next().copied(),
+            };
+        }
+
+        Decompiler {
+            analysis,
+            cfg,
+            labels,
+            signatures,
+            live: dataflow::liveness(analysis, cfg),
+            loops,
+            joins,
+            lines: Vec::new(),
+            emitted: BTreeSet::new(),
+            gotos: BTreeSet::new(),
+            temps: 0,
+        }
+    }
+
+    fn emit(&mut self, depth: usize, line: String) {
+        self.lines.push(format!("{}{}", "    ".repeat(depth), line));
+    }
+
+    /// Writes out the pending register values that `select` picks, ordering them so each
+    /// reads registers before they're overwritten. Returns the assignments, and keeps
+    /// `keep` (expressions still to be printed) reading the right values.
+    fn flush(
+        &mut self,
+        values: &mut [Expr; 8],
+        select: impl Fn(usize, &Expr) -> bool,
+        keep: &mut [Expr],
+        depth: usize,
+    ) -> Vec<String> {
+        let mut pending: Vec<(usize, Expr)> = (0..8)
+            .filter(|&r| values[r] != Expr::Reg(r) && select(r, &values[r]))
+            .map(|r| (r, values[r].clone()))
+            .collect();
+
+        // Values that aren't written out now still need to read the old registers
+        let mut others: Vec<(usize, Expr)> = (0..8)
+            .filter(|&r| values[r] != Expr::Reg(r) && !pending.iter().any(|(p, _)| *p == r))
+            .map(|r| (r, values[r].clone()))
+            .collect();
+
+        let mut assignments = Vec::new();
+
+        while !pending.is_empty() {
+            // Whether something else still needs the old value of `r`, once any copies of its
+            // new value have been swapped for the register itself
+            let blocked = |r: usize, value: &Expr, pending: &[(usize, Expr)], others: &[(usize, Expr)], keep: &[Expr]| {
+                let uses = |e: &Expr| {
+                    let mut e = e.clone();
+                    if value.size() > 1 {
+                        e.replace(value, &Expr::Temp(usize::MAX));
+                    }
+                    e.uses(r)
+                };
+
+                pending.iter().any(|(p, e)| *p != r && uses(e))
+                    || others.iter().any(|(_, e)| uses(e))
+                    || keep.iter().any(uses)
+            };
+
+            let next = (0..pending.len())
+                .filter(|&i| !blocked(pending[i].0, &pending[i].1, &pending, &others, keep))
+                .min_by_key(|&i| pending[i].1.size());
+
+            let index = match next {
+                Some(index) => index,
+                None => {
+                    // A cycle, like a swap: save one register first
+                    let r = pending[0].0;
+                    let temp = Expr::Temp(self.temps);
+                    self.temps += 1;
+                    self.emit(depth, format!("{} = r{};", temp, r));
+
+                    let old = Expr::Reg(r);
+                    for e in pending.iter_mut().skip(1).map(|(_, e)| e).chain(others.iter_mut().map(|(_, e)| e)).chain(keep.iter_mut()) {
+                        e.replace(&old, &temp);
+                    }
+                    continue;
+                }
+            };
+
+            let (r, value) = pending.remove(index);
+            assignments.push(format!("r{} = {}", r, value));
+            values[r] = Expr::Reg(r);
+
+            // Later expressions can refer to the register instead of repeating the value
+            if value.size() > 1 {
+                let register = Expr::Reg(r);
+                for e in pending.iter_mut().map(|(_, e)| e).chain(others.iter_mut().map(|(_, e)| e)).chain(keep.iter_mut()) {
+                    e.replace(&value, &register);
+                }
+            }
+        }
+
+        for (r, value) in others {
+            values[r] = value;
+        }
+
+        assignments
+    }
+
+    fn flush_lines(&mut self, values: &mut [Expr; 8], select: impl Fn(usize, &Expr) -> bool, keep: &mut [Expr], depth: usize) {
+        for assignment in self.flush(values, select, keep, depth) {
+            self.emit(depth, format!("{};", assignment));
+        }
+    }
+
+    /// Writes out a block's statements, returning the condition it branches on, if any.
+    fn block(&mut self, start: usize, depth: usize) -> Option<Expr> {
+        let block = &self.cfg.blocks[&start];
+        let live_out = self.live[&start].1;
+        let mut values: [Expr; 8] = [0, 1, 2, 3, 4, 5, 6, 7].map(Expr::Reg);
+
+        for address in block.instructions.clone() {
+            let instruction: &Decoded = &self.analysis.code[&address];
+            let effect = match instruction.lift() {
+                Some(effect) => effect,
+                None => continue,
+            };
+            let operand = |values: &[Expr; 8], value: Operand| match value {
+                Operand::Register(r) => values[r].clone(),
+                Operand::Literal(word) | Operand::Invalid(word) => Expr::Const(word),
+            };
+
+            match effect {
+                Effect::Assign(r, ir::Expr::Copy(a)) => values[r] = operand(&values, a),
+                Effect::Assign(r, ir::Expr::Binary(opcode, a, b)) => {
+                    values[r] = Expr::binary(Op::of(opcode), operand(&values, a), operand(&values, b));
+                }
+                Effect::Assign(r, ir::Expr::Not(a)) => values[r] = Expr::not(operand(&values, a)),
+                Effect::Load(r, from) => values[r] = Expr::Mem(Box::new(operand(&values, from))),
+                Effect::Push(value) => {
+                    let value = operand(&values, value);
+                    self.emit(depth, format!("push({});", value));
+                }
+                Effect::Out(value) => {
+                    let value = match operand(&values, value) {
+                        Expr::Const(c) if (32..127).contains(&c) && c != 39 && c != 92 => format!("'{}'", c as u8 as char),
+                        Expr::Const(10) => "'\\n'".to_owned(),
+                        value => value.to_string(),
+                    };
+                    self.emit(depth, format!("out({});", value));
+                }
+                Effect::Pop(r) | Effect::In(r) => {
+                    // Whatever the register held is gone, and anything else is written out first
+                    values[r] = Expr::Reg(r);
+                    self.flush_lines(&mut values, |_, _| true, &mut [], depth);
+
+                    let call = if let Effect::Pop(_) = effect { "pop" } else { "in" };
+                    self.emit(depth, format!("r{} = {}();", r, call));
+                }
+                Effect::Store(to, value) => {
+                    let mut keep = vec![operand(&values, to), operand(&values, value)];
+                    self.flush_lines(&mut values, |_, e| e.reads_memory(), &mut keep, depth);
+                    self.emit(depth, format!("mem[{}] = {};", keep[0], keep[1]));
+                }
+                Effect::Call(target) => {
+                    // The callee might read any register, so everything pending is written out first
+                    let mut keep = vec![operand(&values, target)];
+                    self.flush_lines(&mut values, |_, _| true, &mut keep, depth);
+
+                    // A register holding a known address is as good as a direct call
+                    let known = match keep[0] {
+                        Expr::Const(c) => Some(c as usize),
+                        _ => None,
+                    };
+                    let callee = match instruction.target().or(known) {
+                        Some(target) => identifier(target, self.labels),
+                        None => format!("(*{})", keep[0]),
+                    };
+                    let arguments = match instruction.target().or(known).and_then(|t| self.signatures.get(&t)) {
+                        Some(signature) => convention::list(signature.arguments).join(", "),
+                        None => "...".to_owned(),
+                    };
+                    self.emit(depth, format!("{}({});", callee, arguments));
+                }
+                Effect::Ret => {
+                    self.flush_lines(&mut values, |_, _| true, &mut [], depth);
+                    self.emit(depth, "return;".to_owned());
+                }
+                Effect::Halt => {
+                    self.flush_lines(&mut values, |_, _| true, &mut [], depth);
+                    self.emit(depth, "halt();".to_owned());
+                }
+                Effect::Branch { condition, target, .. } => {
+                    let mut keep = vec![operand(&values, condition), operand(&values, target)];
+                    self.flush_lines(&mut values, |r, _| dataflow::contains(live_out, r), &mut keep, depth);
+                    return Some(keep.remove(0));
+                }
+                Effect::Jump(target) => {
+                    let mut keep = vec![operand(&values, target)];
+                    self.flush_lines(&mut values, |r, _| dataflow::contains(live_out, r), &mut keep, depth);
+                    if instruction.target().is_none() {
+                        self.emit(depth, format!("goto *{};", keep[0]));
+                    }
+                    return None;
+                }
+                Effect::Noop => {}
+            }
+        }
+
+        self.flush_lines(&mut values, |r, _| dataflow::contains(live_out, r), &mut [], depth);
+        None
+    }
+
+    /// Handles control passing to `to`: returns it if the region should carry on there,
+    /// or writes the `continue`, `break` or `goto` that gets there and returns `None`.
+    fn goto(&mut self, to: usize, end: Option<usize>, current: Option<usize>, depth: usize) -> Option<usize> {
+        if Some(to) == end {
+            return None;
+        }
+
+        if let Some(header) = current {
+            let l = &self.loops[&header];
+            if to == header {
+                self.emit(depth, "continue;".to_owned());
+                return None;
+            }
+            if Some(to) == l.follow {
+                self.emit(depth, "break;".to_owned());
+                return None;
+            }
+            if !l.body.contains(&to) {
+                self.gotos.insert(to);
+                self.emit(depth, format!("goto L{};", to));
+                return None;
+            }
+        }
+
+        if self.emitted.contains(&to) {
+            self.gotos.insert(to);
+            self.emit(depth, format!("goto L{};", to));
+            return None;
+        }
+
+        Some(to)
+    }
+
+    /// Writes out the blocks from `start` until control reaches `end`, inside the loop headed
+    /// by `current` if there is one.
+    fn region(&mut self, start: usize, end: Option<usize>, current: Option<usize>, depth: usize) {
+        let mut b = match self.goto(start, end, current, depth) {
+            Some(b) => b,
+            None => return,
+        };
+
+        loop {
+            if self.loops.contains_key(&b) && current != Some(b) {
+                self.emit(depth, "while (true) {".to_owned());
+                self.body(b, Some(b), depth + 1);
+                // Running off the end of the body goes round again anyway
+                if self.lines.last().map(|l| l.trim()) == Some("continue;") {
+                    self.lines.pop();
+                }
+                self.emit(depth, "}".to_owned());
+
+                b = match self.loops[&b].follow.and_then(|f| self.goto(f, end, current, depth)) {
+                    Some(next) => next,
+                    None => return,
+                };
+                continue;
+            }
+
+            match self.step(b, end, current, depth) {
+                Some(next) => b = next,
+                None => return,
+            }
+        }
+    }
+
+    /// The loop body, starting with its header.
+    fn body(&mut self, header: usize, current: Option<usize>, depth: usize) {
+        if let Some(next) = self.step(header, None, current, depth) {
+            self.region(next, None, current, depth);
+        }
+    }
+
+    /// Writes out one block and decides where to go next.
+    fn step(&mut self, b: usize, end: Option<usize>, current: Option<usize>, depth: usize) -> Option<usize> {
+        self.emitted.insert(b);
+        if self.gotos.contains(&b) {
+            self.emit(depth.saturating_sub(1), format!("L{}:", b));
+        }
+
+        let condition = self.block(b, depth);
+        let successors = self.cfg.blocks[&b].successors.clone();
+
+        match (successors.as_slice(), condition) {
+            ([(Target::Block(next), _)], _) => self.goto(*next, end, current, depth),
+            ([(Target::Function(f), _)], _) => {
+                let name = identifier(*f, self.labels);
+                self.emit(depth, format!("goto {}; // tail call", name));
+                None
+            }
+            ([(Target::Exit, _)], _) | ([(Target::Indirect, _)], _) => None,
+            ([first, second], Some(condition)) => {
+                let (taken, other) = if first.1 == "true" { (first.0, second.0) } else { (second.0, first.0) };
+                self.branch(b, condition, taken, other, end, current, depth)
+            }
+            _ => None,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn branch(
+        &mut self,
+        b: usize,
+        condition: Expr,
+        taken: Target,
+        other: Target,
+        end: Option<usize>,
+        current: Option<usize>,
+        depth: usize,
+    ) -> Option<usize> {
+        let outside = |t: Target, labels| match t {
+            Target::Function(f) => Some(format!("goto {}; // tail call", identifier(f, labels))),
+            Target::Indirect => Some("goto *?;".to_owned()),
+            _ => None,
+        };
+
+        let (taken, other) = match (taken, other) {
+            (Target::Block(t), Target::Block(o)) => (t, o),
+            (t, Target::Block(o)) => {
+                self.emit(depth, format!("if ({}) {{", condition.condition(false)));
+                self.emit(depth + 1, outside(t, self.labels).unwrap());
+                self.emit(depth, "}".to_owned());
+                return self.goto(o, end, current, depth);
+            }
+            (Target::Block(t), o) => {
+                self.emit(depth, format!("if ({}) {{", condition.condition(true)));
+                self.emit(depth + 1, outside(o, self.labels).unwrap());
+                self.emit(depth, "}".to_owned());
+                return self.goto(t, end, current, depth);
+            }
+            _ => return None,
+        };
+
+        // A branch that leaves the loop or jumps back is written as a one-liner, and the other carries on
+        let leaves = |to: usize, this: &Self| {
+            let outside_loop = current.map(|h| to == h || !this.loops[&h].body.contains(&to)).unwrap_or(false);
+            Some(to) != end && (outside_loop || this.emitted.contains(&to))
+        };
+        for (exit, stay, negate) in [(taken, other, false), (other, taken, true)] {
+            if leaves(exit, self) {
+                self.emit(depth, format!("if ({}) {{", condition.condition(negate)));
+                self.goto(exit, end, current, depth + 1);
+                self.emit(depth, "}".to_owned());
+                return self.goto(stay, end, current, depth);
+            }
+        }
+
+        // Stay inside the loop: a join outside it is reached with `break` instead
+        let join = self.joins.get(&b).copied().filter(|j| match current {
+            Some(header) => self.loops[&header].body.contains(j),
+            None => true,
+        });
+
+        if Some(taken) == join {
+            self.emit(depth, format!("if ({}) {{", condition.condition(true)));
+            self.region(other, join, current, depth + 1);
+        } else {
+            self.emit(depth, format!("if ({}) {{", condition.condition(false)));
+            self.region(taken, join, current, depth + 1);
+
+            if Some(other) != join {
+                self.emit(depth, "} else {".to_owned());
+                self.region(other, join, current, depth + 1);
+            }
+        }
+        self.emit(depth, "}".to_owned());
+
+        join.and_then(|j| self.goto(j, end, current, depth))
+    }
+}
+
+/// Structured pseudocode for `function`, with its inferred signature from `signatures` in the
+/// header.
+pub fn decompile(
+    analysis: &Analysis,
+    function: &Function,
+    signatures: &BTreeMap<usize, Signature>,
+    labels: &HashMap<usize, String>,
+) -> Vec<String> {
+    let signature = &signatures[&function.entry];
+    let cfg = Cfg::build(analysis, function);
+    let mut decompiler = Decompiler::new(analysis, &cfg, labels, signatures);
+
+    // The first pass finds out which blocks need labels for the second
+    decompiler.region(cfg.entry, None, None, 1);
+    let gotos = std::mem::take(&mut decompiler.gotos);
+
+    decompiler.lines.clear();
+    decompiler.emitted.clear();
+    decompiler.temps = 0;
+    decompiler.gotos = gotos;
+    decompiler.region(cfg.entry, None, None, 1);
+
+    let range = format!("// {}-{}", function.entry, function.end(analysis));
+    let mut lines = vec![
+        match signature.saves() {
+            Some(saves) => format!("{}, {}", range, saves),
+            None => range,
+        },
+        format!("fn {}{} {{", identifier(function.entry, labels), signature.prototype()),
+    ];
+    lines.append(&mut decompiler.lines);
+    lines.push("}".to_owned());
+    lines
+}
@@ -1,16 +1,30 @@
-use crate::{exec::Instruction, opcode::Opcode};
+use crate::opcode::{Opcode, Operand};
 use super::util;
+use super::convention::Signature;
+use super::exec::Instruction;
+use super::strings;
+use super::xref::Xrefs;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Deref;
+
+// Shortest run of printable words that's rendered as a string rather than as words.
+const MIN_STRING_LENGTH: usize = 4;
+const DATA_PER_LINE: usize = 8;
 
 fn process_arg(arg: u16) -> String {
   if let Some(ascii) = util::to_ascii(arg) {
-    format!("({}){}", ascii, arg)
+    // Keep control characters from breaking the listing across lines
+    if ascii.is_control() {
+      arg.to_string()
+    } else {
+      format!("({}){}", ascii, arg)
+    }
   } else {
-    let arg = util::register_pretty(&arg);
-    arg
+    util::register_pretty(&arg)
   }
 }
 
-fn print_instruction(ip: usize, raw: u16, opcode: &Opcode, args: Vec<u16>) {
+fn format_operation(raw: u16, opcode: &Opcode, args: &[u16]) -> String {
   let args = args.iter().map(|n| process_arg(*n));
   let args: Vec<_> = args.collect();
   let args = args.join(", ");
@@ -20,25 +34,404 @@ fn print_instruction(ip: usize, raw: u16, opcode: &Opcode, args: Vec<u16>) {
     _ => opcode.to_string()
   };
 
-  println!("{:5}: {} {}", ip, opcode_str, args);
+  format!("{} {}", opcode_str, args)
+}
 
-  match opcode {
-    Opcode::Ret => println!(""),
-    _ => ()
-  }
+fn format_instruction(ip: usize, raw: u16, opcode: &Opcode, args: &[u16]) -> String {
+  format!("{:5}: {}", ip, format_operation(raw, opcode, args))
 }
 
-pub fn disassemble_instructions(instructions: Vec<u16>) {
+/// Linear sweep over `instructions`, returning each decoded instruction's address and text.
+pub fn listing(instructions: &[u16]) -> Vec<(usize, String)> {
+  let mut lines = Vec::new();
   let mut ip = 0;
 
   while ip < instructions.len() {
     let opcode = Opcode::from(instructions[ip]);
-    ip += 1;
+    let end = (ip + 1 + opcode.arg_count()).min(instructions.len());
+    let args = &instructions[ip + 1..end];
+
+    lines.push((ip, format_instruction(ip, instructions[ip], &opcode, args)));
+
+    ip = end;
+  }
+
+  lines
+}
+
+pub fn disassemble_instructions(instructions: Vec<u16>) {
+  for (ip, line) in listing(&instructions) {
+    println!("{}", line);
+
+    if let Opcode::Ret = Opcode::from(instructions[ip]) {
+      println!();
+    }
+  }
+}
+
+/// A single instruction decoded straight from memory, along with where it was found.
+#[derive(Clone)]
+pub struct Decoded {
+  pub address: usize,
+  pub instruction: Instruction,
+}
+
+impl Deref for Decoded {
+  type Target = Instruction;
+
+  fn deref(&self) -> &Instruction {
+    &self.instruction
+  }
+}
+
+impl Decoded {
+  pub fn next(&self) -> usize {
+    self.address + self.size()
+  }
+
+  /// The instruction as it appears in a listing, without its address.
+  pub fn operation(&self) -> String {
+    let words: Vec<_> = self.operands.iter().map(|o| o.word()).collect();
+    format_operation(self.opcode.code().unwrap(), &self.opcode, &words)
+  }
+
+  /// The literal jump or call target, or `None` if there isn't one or it's in a register.
+  pub fn target(&self) -> Option<usize> {
+    self.lift()?.target()?.literal().map(|t| t as usize)
+  }
+
+  /// Whether this transfers control to an address held in a register.
+  pub fn is_indirect(&self) -> bool {
+    matches!(self.lift().and_then(|e| e.target()), Some(Operand::Register(_)))
+  }
+
+  /// Whether execution can continue with the next instruction in memory.
+  pub fn falls_through(&self) -> bool {
+    self.lift().is_none_or(|e| e.falls_through())
+  }
+}
+
+/// Decodes the instruction at `address`, if it's a known opcode with all of its arguments in memory.
+pub fn decode(memory: &[u16], address: usize) -> Option<Decoded> {
+  let instruction = Instruction::decode(memory.get(address..)?)?;
+  Some(Decoded { address, instruction })
+}
+
+/// The result of following control flow through a program image.
+pub struct Analysis {
+  pub code: BTreeMap<usize, Decoded>,
+  /// Jumps and calls through a register, whose targets aren't known statically.
+  pub unresolved: BTreeSet<usize>,
+  /// Addresses control flow reaches that don't hold a valid instruction.
+  pub invalid: BTreeSet<usize>,
+}
+
+impl Analysis {
+  pub fn is_code(&self, address: usize) -> bool {
+    self.code.contains_key(&address)
+  }
+}
+
+/// Recursive-descent disassembly: follows `Jmp`/`Jt`/`Jf`/`Call` targets and fallthroughs
+/// from `entries`, so anything never reached is left as data.
+pub fn recursive_descent(memory: &[u16], entries: &[usize]) -> Analysis {
+  let mut analysis = Analysis {
+    code: BTreeMap::new(),
+    unresolved: BTreeSet::new(),
+    invalid: BTreeSet::new(),
+  };
+
+  let mut pending: Vec<usize> = entries.to_vec();
+
+  while let Some(address) = pending.pop() {
+    if analysis.is_code(address) || analysis.invalid.contains(&address) {
+      continue;
+    }
+
+    let instruction = match decode(memory, address) {
+      Some(instruction) => instruction,
+      None => {
+        analysis.invalid.insert(address);
+        continue;
+      }
+    };
+
+    if instruction.falls_through() {
+      pending.push(instruction.next());
+    }
+
+    if let Some(target) = instruction.target() {
+      pending.push(target);
+    } else if instruction.is_indirect() {
+      analysis.unresolved.insert(address);
+    }
+
+    analysis.code.insert(address, instruction);
+  }
+
+  analysis
+}
+
+fn join(addresses: &BTreeSet<usize>) -> String {
+  addresses.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+fn is_printable(word: u16) -> bool {
+  (32..127).contains(&word) || word == 10
+}
+
+/// Extra information woven into a rendered listing.
+pub struct Annotations<'a> {
+  pub labels: &'a HashMap<usize, String>,
+  /// The image as it was loaded, so words that have changed since can be marked with `*`.
+  pub original: Option<&'a [u16]>,
+  pub xrefs: Option<&'a Xrefs>,
+  /// Operands holding the address of one of these are followed by its text.
+  pub strings: Option<&'a strings::Table>,
+  /// Extra comments for particular instructions, by address.
+  pub comments: Option<&'a BTreeMap<usize, String>>,
+  /// Inferred calling conventions, shown above each function.
+  pub signatures: Option<&'a BTreeMap<usize, Signature>>,
+  /// Where indirect calls and jumps go, by site, as far as that's known.
+  pub targets: Option<&'a BTreeMap<usize, BTreeSet<usize>>>,
+}
+
+struct Writer<'a> {
+  memory: &'a [u16],
+  annotations: &'a Annotations<'a>,
+  lines: Vec<String>,
+  /// Write the syntax `vm asm` accepts, labelling these jump and call targets.
+  canonical: Option<&'a BTreeSet<usize>>,
+  /// The address each line of code or data starts at.
+  starts: BTreeSet<usize>,
+}
+
+impl<'a> Writer<'a> {
+  fn emit(&mut self, address: usize, length: usize, text: String) {
+    self.starts.insert(address);
+
+    if self.canonical.is_some() {
+      // The address and any annotations go in a comment
+      let xrefs = self.annotations.xrefs.and_then(|x| x.describe(address));
+      let xrefs = xrefs.map(|x| format!(" ; xref: {}", x)).unwrap_or_default();
+      // Directives may have `;` in their strings, but instructions only have it before comments
+      let (operation, comment) = match text.split_once(" ; ") {
+        Some((operation, comment)) if !text.starts_with('.') => (operation, format!(" ; {}", comment)),
+        _ => (text.as_str(), String::new()),
+      };
+
+      self.lines.push(format!("    {:<32} ; {}{}{}", operation, address, comment, xrefs));
+      return;
+    }
+
+    let marker = match self.annotations.original {
+      Some(original) => {
+        let changed = (address..address + length).any(|a| original.get(a) != self.memory.get(a));
+        if changed { "*" } else { " " }
+      }
+      None => "",
+    };
+
+    let xrefs = self.annotations.xrefs.and_then(|x| x.describe(address));
+
+    match xrefs {
+      Some(xrefs) => self.lines.push(format!("{}{:5}: {} ; xref: {}", marker, address, text, xrefs)),
+      None => self.lines.push(format!("{}{:5}: {}", marker, address, text)),
+    }
+  }
+
+  fn push_words(&mut self, start: usize, words: &mut Vec<u16>) {
+    if !words.is_empty() {
+      let text: Vec<_> = words.iter().map(|w| w.to_string()).collect();
+      self.emit(start, words.len(), format!(".data {}", text.join(", ")));
+      words.clear();
+    }
+  }
+
+  fn data(&mut self, start: usize, end: usize) {
+    let memory = self.memory;
+    let mut words = Vec::new();
+    let mut row_start = start;
+    let mut address = start;
+
+    while address < end {
+      let run = memory[address..end].iter().take_while(|w| is_printable(**w)).count();
+
+      if run >= MIN_STRING_LENGTH {
+        self.push_words(row_start, &mut words);
+
+        let text: String = memory[address..address + run].iter().map(|w| *w as u8 as char).collect();
+        self.emit(address, run, format!(".string {:?}", text));
+        address += run;
+        continue;
+      }
+
+      if words.is_empty() {
+        row_start = address;
+      }
+
+      words.push(memory[address]);
+      address += 1;
+
+      if words.len() == DATA_PER_LINE {
+        self.push_words(row_start, &mut words);
+      }
+    }
+
+    self.push_words(row_start, &mut words);
+  }
+}
+
+/// An instruction in the syntax `vm asm` accepts, with targets in `targets` as labels.
+fn canonical_operation(instruction: &Decoded, targets: &BTreeSet<usize>) -> String {
+  let args: Vec<_> = instruction
+    .operands
+    .iter()
+    .map(|&operand| match operand {
+      Operand::Register(_) => util::register_pretty(&operand.word()),
+      Operand::Literal(word) if instruction.target() == Some(word as usize) && targets.contains(&(word as usize)) => {
+        format!("L{}", word)
+      }
+      _ => operand.word().to_string(),
+    })
+    .collect();
+
+  format!("{} {}", instruction.opcode.mnemonic().unwrap(), args.join(", ")).trim_end().to_owned()
+}
+
+/// Renders a recursive-descent disassembly: code as instructions, everything else as data.
+pub fn render(memory: &[u16], analysis: &Analysis, annotations: &Annotations) -> Vec<String> {
+  render_with(memory, analysis, annotations, None).lines
+}
+
+/// Renders the same disassembly as `render`, but in a syntax `vm asm` turns back into exactly
+/// the same words: spec mnemonics, plain numbers, and an `L<address>:` label on every
+/// jump or call target, with addresses and annotations in comments.
+pub fn canonical(memory: &[u16], analysis: &Analysis, annotations: &Annotations) -> Vec<String> {
+  let starts = render_with(memory, analysis, annotations, None).starts;
+
+  // Only targets that start a line can be labelled
+  let targets: BTreeSet<usize> = analysis
+    .code
+    .values()
+    .filter_map(Decoded::target)
+    .filter(|t| starts.contains(t))
+    .collect();
+
+  render_with(memory, analysis, annotations, Some(&targets)).lines
+}
+
+fn render_with<'a>(
+  memory: &'a [u16],
+  analysis: &Analysis,
+  annotations: &'a Annotations,
+  canonical: Option<&'a BTreeSet<usize>>,
+) -> Writer<'a> {
+  let labels = annotations.labels;
+  let mut writer = Writer {
+    memory,
+    annotations,
+    lines: Vec::new(),
+    canonical,
+    starts: BTreeSet::new(),
+  };
+  let mut address = 0;
+
+  while address < memory.len() {
+    if let Some(label) = labels.get(&address) {
+      writer.lines.push(format!("       ; {}", label));
+    }
+    if let Some(signature) = annotations.signatures.and_then(|s| s.get(&address)) {
+      writer.lines.push(format!("       ; signature {}", signature));
+    }
+    if canonical.map(|t| t.contains(&address)).unwrap_or(false) {
+      writer.lines.push(format!("L{}:", address));
+    }
+
+    match analysis.code.get(&address) {
+      // The assembler won't take operands outside the valid range, so leave those as data
+      Some(instruction) if canonical.is_some() && instruction.operands.iter().any(|o| matches!(o, Operand::Invalid(_))) => {
+        writer.data(address, instruction.next());
+        address = instruction.next();
+      }
+      Some(instruction) => {
+        let mut text = match canonical {
+          Some(targets) => canonical_operation(instruction, targets),
+          None => instruction.operation(),
+        };
+        match annotations.targets.and_then(|t| t.get(&address)) {
+          Some(targets) => text.push_str(&format!(" ; indirect target {}", join(targets))),
+          None if analysis.unresolved.contains(&address) => text.push_str(" ; unresolved indirect target"),
+          None => {}
+        }
+        let string = annotations.strings.and_then(|s| {
+          instruction.operands.iter().find_map(|o| s.get(o.literal()? as usize))
+        });
+        if let Some(string) = string {
+          text.push_str(&format!(" ; {}", string.describe()));
+        }
+        if let Some(comment) = annotations.comments.and_then(|c| c.get(&address)) {
+          text.push_str(&format!(" ; {}", comment));
+        }
+        writer.emit(address, instruction.size(), text);
+
+        if let Opcode::Ret = instruction.opcode {
+          writer.lines.push(String::new());
+        }
+
+        address = instruction.next();
+      }
+      None => {
+        // Start a new line wherever there's something to say about an address
+        let referenced = |a: &usize| annotations.xrefs.map(|x| x.is_referenced(*a)).unwrap_or(false);
+        let end = (address + 1..memory.len())
+          .find(|a| analysis.is_code(*a) || labels.contains_key(a) || referenced(a))
+          .unwrap_or(memory.len());
 
-    let args = &instructions[ip..ip + opcode.arg_count()];
+        writer.data(address, end);
+        address = end;
+      }
+    }
+  }
 
-    print_instruction(ip - 1, instructions[ip - 1], &opcode, args.to_vec());
+  writer
+}
 
-    ip += opcode.arg_count();
+pub fn disassemble_recursive(memory: &[u16], entries: &[usize], annotations: &Annotations, canonical: bool) {
+  let analysis = recursive_descent(memory, entries);
+  let mut xrefs = Xrefs::build(&analysis);
+  if let Some(targets) = annotations.targets {
+    xrefs.add_indirect(&analysis, targets);
+  }
+  let annotations = &Annotations {
+    xrefs: annotations.xrefs.or(Some(&xrefs)),
+    ..*annotations
+  };
+
+  println!("; {} instructions reached from {:?}", analysis.code.len(), entries);
+  if let Some(original) = annotations.original {
+    let changed = (0..memory.len()).filter(|&a| original.get(a) != memory.get(a)).count();
+    let marked = if canonical { "" } else { " (marked with *)" };
+    println!("; {} words differ from the original image{}", changed, marked);
+  }
+  for address in &analysis.unresolved {
+    match annotations.targets.and_then(|t| t.get(address)) {
+      Some(targets) => println!("; indirect target at {} resolved to {}", address, join(targets)),
+      None => println!("; unresolved indirect target at {}", address),
+    }
+  }
+  for address in &analysis.invalid {
+    println!("; control flow reaches invalid instruction at {}", address);
   }
-}
\ No newline at end of file
+  println!();
+
+  let lines = if canonical {
+    self::canonical(memory, &analysis, annotations)
+  } else {
+    render(memory, &analysis, annotations)
+  };
+
+  for line in lines {
+    println!("{}", line);
+  }
+}
@@ -1,246 +1,48 @@
 use super::debug::Debugger;
+use super::ir::{self, Effect};
 use super::util;
-use super::opcode::Opcode;
+use super::opcode::{Opcode, Operand, Role};
+use super::taint::Shadow;
+use super::tui;
 
-use std::{
-    collections::{HashMap, HashSet},
-    fmt::Debug,
-};
+use std::collections::{HashMap, VecDeque};
 
+#[derive(Clone)]
 pub struct Instruction {
     pub opcode: Opcode,
-    pub unresolved_args: Vec<u16>,
-    pub args: Vec<u16>,
+    pub operands: Vec<Operand>,
 }
 
 impl Instruction {
-    pub fn build(opcode: Opcode, state: &mut State) -> Instruction {
-        match opcode {
-            Opcode::Halt => Instruction {
-                opcode,
-                args: vec![],
-                unresolved_args: vec![],
-            },
-            Opcode::Add => {
-                let values = state.read_many(3);
-                let a = values[0];
-                let b = state.resolve_value(values[1]);
-                let c = state.resolve_value(values[2]);
-                Instruction {
-                    opcode,
-                    args: vec![a, b, c],
-                    unresolved_args: values,
-                }
-            }
-            Opcode::Mult => {
-                let values = state.read_many(3);
-                let a = values[0];
-                let b = state.resolve_value(values[1]);
-                let c = state.resolve_value(values[2]);
-
-                Instruction {
-                    opcode,
-                    unresolved_args: values,
-                    args: vec![a, b, c],
-                }
-            }
-            Opcode::Mod => {
-                let values = state.read_many(3);
-                let a = values[0];
-                let b = state.resolve_value(values[1]);
-                let c = state.resolve_value(values[2]);
-
-                Instruction {
-                    opcode,
-                    unresolved_args: values,
-                    args: vec![a, b, c],
-                }
-            }
-            Opcode::Push => {
-                let value = state.read_next();
-                let a = state.resolve_value(value);
-
-                Instruction {
-                    opcode,
-                    unresolved_args: vec![value],
-                    args: vec![a],
-                }
-            }
-            Opcode::Pop => {
-                let a = state.read_next();
+    /// Decodes the instruction at the start of `words`. Returns `None` if it isn't a known
+    /// opcode with all of its operands there.
+    pub fn decode(words: &[u16]) -> Option<Instruction> {
+        let opcode = Opcode::from(*words.first()?);
 
-                Instruction {
-                    opcode,
-                    unresolved_args: vec![a],
-                    args: vec![a],
-                }
-            }
-            Opcode::Gt => {
-                let values = state.read_many(3);
-                let a = values[0];
-                let b = state.resolve_value(values[1]);
-                let c = state.resolve_value(values[2]);
-
-                Instruction {
-                    opcode,
-                    unresolved_args: values,
-                    args: vec![a, b, c],
-                }
-            }
-            Opcode::Eq => {
-                let values = state.read_many(3);
-                let a = values[0];
-                let b = state.resolve_value(values[1]);
-                let c = state.resolve_value(values[2]);
-
-                Instruction {
-                    opcode,
-                    unresolved_args: values,
-                    args: vec![a, b, c],
-                }
-            }
-            Opcode::And => {
-                let values = state.read_many(3);
-                let a = values[0];
-                let b = state.resolve_value(values[1]);
-                let c = state.resolve_value(values[2]);
-
-                Instruction {
-                    opcode,
-                    unresolved_args: values,
-                    args: vec![a, b, c],
-                }
-            }
-            Opcode::Or => {
-                let values = state.read_many(3);
-                let a = values[0];
-                let b = state.resolve_value(values[1]);
-                let c = state.resolve_value(values[2]);
-
-                Instruction {
-                    opcode,
-                    unresolved_args: values,
-                    args: vec![a, b, c],
-                }
-            }
-            Opcode::Not => {
-                let values = state.read_many(2);
-                let a = values[0];
-                let b = state.resolve_value(values[1]);
-
-                Instruction {
-                    opcode,
-                    unresolved_args: values,
-                    args: vec![a, b],
-                }
-            }
-            Opcode::Set => {
-                let values = state.read_many(2);
-                let a = values[0];
-                let b = state.resolve_value(values[1]);
-
-                Instruction {
-                    opcode,
-                    unresolved_args: values,
-                    args: vec![a, b],
-                }
-            }
-            Opcode::Jmp => {
-                let to = state.read_next();
-
-                Instruction {
-                    opcode,
-                    unresolved_args: vec![to],
-                    args: vec![to],
-                }
-            }
-            Opcode::JmpIfTrue => {
-                let values = state.read_many(2);
-                let a = state.resolve_value(values[0]);
-                let b = state.resolve_value(values[1]);
-
-                Instruction {
-                    opcode,
-                    unresolved_args: values,
-                    args: vec![a, b],
-                }
-            }
-            Opcode::JmpIfFalse => {
-                let values = state.read_many(2);
-                let a = state.resolve_value(values[0]);
-                let b = state.resolve_value(values[1]);
-
-                Instruction {
-                    opcode,
-                    unresolved_args: values,
-                    args: vec![a, b],
-                }
-            }
-            Opcode::Call => {
-                let value = state.read_next();
-                let a = state.resolve_value(value);
-
-                Instruction {
-                    opcode,
-                    unresolved_args: vec![value],
-                    args: vec![a],
-                }
-            }
-            Opcode::RMem => {
-                let values = state.read_many(2);
-                let a = values[0];
-                let b = state.resolve_value(values[1]);
-
-                Instruction {
-                    opcode,
-                    unresolved_args: values,
-                    args: vec![a, b],
-                }
-            }
-            Opcode::WMem => {
-                let values = state.read_many(2);
-                let a = state.resolve_value(values[0]);
-                let b = state.resolve_value(values[1]);
-
-                Instruction {
-                    opcode,
-                    unresolved_args: values,
-                    args: vec![a, b],
-                }
-            }
-            Opcode::Ret => Instruction {
-                opcode,
-                unresolved_args: vec![],
-                args: vec![],
-            },
-            Opcode::In => {
-                let a = state.read_next();
-
-                Instruction {
-                    opcode,
-                    unresolved_args: vec![a],
-                    args: vec![a],
-                }
-            }
-            Opcode::Out => {
-                let byte = state.read_next();
-                let resolved_byte = state.resolve_value(byte);
-
-                Instruction {
-                    opcode,
-                    unresolved_args: vec![byte],
-                    args: vec![resolved_byte],
-                }
-            }
-            Opcode::Unknown => {
-                panic!("Unknown opcode");
-            },
-            Opcode::Noop => Instruction {
-                opcode,
-                unresolved_args: vec![],
-                args: vec![],
-            },
+        if let Opcode::Unknown = opcode {
+            return None;
         }
+
+        let operands = words.get(1..=opcode.arg_count())?;
+
+        Some(Instruction {
+            opcode,
+            operands: operands.iter().map(|&w| Operand::from_word(w)).collect(),
+        })
+    }
+
+    /// The words the instruction is stored as.
+    pub fn encode(&self) -> Vec<u16> {
+        let operands = self.operands.iter().map(|o| o.word());
+        std::iter::once(self.opcode.code().unwrap()).chain(operands).collect()
+    }
+
+    pub fn size(&self) -> usize {
+        self.operands.len() + 1
+    }
+
+    pub fn lift(&self) -> Option<Effect> {
+        ir::lift(self.opcode, &self.operands)
     }
 
     fn maybe_qualify_address(&self, n: u16, labels: &HashMap<usize, String>) -> String {
@@ -251,42 +53,69 @@ impl Instruction {
         }
     }
 
-    pub fn disassemble(&self, ip: usize, labels: &HashMap<usize, String>) -> String {
+    /// The instruction with the values its operands have given `registers`, then as written.
+    /// A destination is shown as its register rather than what it holds.
+    pub fn disassemble(&self, ip: usize, labels: &HashMap<usize, String>, registers: &[u16]) -> String {
         let label = if let Some(label) = labels.get(&ip) {
             format!("{}\n", label)
         } else {
             "".to_owned()
         };
 
+        let values = self.operands.iter().zip(self.opcode.roles()).map(|(&operand, &role)| match operand {
+            Operand::Register(r) if role != Role::Destination => registers[r],
+            operand => operand.word(),
+        });
+
         format!(
             "{}{} / {} / {}",
             label,
-            self.opcode.to_string(),
-            self.args
-                .iter()
-                .map(|n| self.maybe_qualify_address(*n, labels))
+            self.opcode,
+            values
+                .map(|n| self.maybe_qualify_address(n, labels))
                 .collect::<Vec<_>>()
                 .join(", "),
-            self.unresolved_args
+            self.operands
                 .iter()
-                .map(|n| util::register_pretty(n))
+                .map(|o| util::register_pretty(&o.word()))
                 .collect::<Vec<_>>()
                 .join(", ")
         )
     }
 }
 
+pub enum Step {
+    Running,
+    Halted,
+    AwaitingInput,
+}
+
 pub struct State {
     pub instructions: HashMap<u16, u16>,
     pub registers: Vec<u16>,
     pub ip: usize,
     pub stack: Vec<u16>,
     pub text_buffer: Option<String>,
-    pub call_stack: Vec<u16>
+    pub call_stack: Vec<u16>,
+    pub output: String,
+    /// How many lines of `output` to keep, or `None` to keep all of it.
+    scrollback: Option<usize>,
+    /// How many newlines `output` holds, counted while `scrollback` is set.
+    output_lines: usize,
+    /// Lines fed to `In` before falling back to stdin.
+    pub input: VecDeque<String>,
+    /// Whether `In` may block on stdin once `input` runs out.
+    pub interactive: bool,
+    /// Whether `Out` writes to stdout, rather than only to `output`.
+    pub echo: bool,
+    /// Which input characters each value came from, when taint tracking is on.
+    pub shadow: Option<Shadow>,
+    /// How many words have been written to memory, so it can be told when memory has changed.
+    pub writes: usize,
 }
 
 impl State {
-    fn build(instructions: Vec<u16>) -> State {
+    pub fn build(instructions: Vec<u16>) -> State {
         let instructions = instructions
             .into_iter()
             .enumerate()
@@ -299,56 +128,58 @@ impl State {
             ip: 0,
             stack: vec![],
             text_buffer: None,
-            call_stack: vec![]
+            call_stack: vec![],
+            output: String::new(),
+            scrollback: None,
+            output_lines: 0,
+            input: VecDeque::new(),
+            interactive: true,
+            echo: true,
+            shadow: None,
+            writes: 0,
         }
     }
 
-    fn opcode(&self) -> Opcode {
-        Opcode::from(self.instructions[&(self.ip as u16)])
-    }
-
-    fn jump_by(&mut self, count: usize) {
-        self.ip += count;
-    }
-
-    fn jump_to(&mut self, to: u16) {
-        self.ip = to as usize;
-    }
-
-    fn set_register(&mut self, index: u16, value: u16) {
-        let register_index = index - 32768;
-        self.registers[register_index as usize] = value;
+    /// Keeps only the last `scrollback` lines of output from now on, or all of it for `None`.
+    pub fn set_scrollback(&mut self, scrollback: Option<usize>) {
+        self.scrollback = scrollback;
+        self.output_lines = self.output.matches('\n').count();
+        self.trim_output();
     }
 
-    fn resolve_value(&self, value: u16) -> u16 {
-        if value <= 32767 {
-            value
-        } else {
-            let register_index = value - 32768;
-            self.registers[register_index as usize]
+    fn trim_output(&mut self) {
+        if let Some(lines) = self.scrollback {
+            while self.output_lines > lines {
+                let first = self.output.find('\n').unwrap();
+                self.output.drain(..=first);
+                self.output_lines -= 1;
+            }
         }
     }
 
-    fn read_next(&mut self) -> u16 {
-        self.read_many(1)[0]
-    }
+    /// A copy of memory as a flat slice, with unwritten addresses reading as zero.
+    pub fn memory(&self) -> Vec<u16> {
+        let size = self.instructions.keys().max().map(|&a| a as usize + 1).unwrap_or(0);
+        let mut memory = vec![0; size];
 
-    fn read_many(&mut self, count: usize) -> Vec<u16> {
-        if count == 0 {
-            panic!("Can't read zero args");
+        for (&address, &value) in &self.instructions {
+            memory[address as usize] = value;
         }
 
-        let mut values = Vec::new();
-
-        self.ip += 1;
+        memory
+    }
 
-        for i in self.ip..self.ip + count {
-            values.push(self.instructions[&(i as u16)]);
-        }
+    /// Decodes the instruction at `address`, if there is a valid one.
+    pub fn instruction(&self, address: usize) -> Option<Instruction> {
+        let words: Vec<u16> = (address..address + 4)
+            .map_while(|a| self.instructions.get(&(a as u16)).copied())
+            .collect();
 
-        self.ip += count;
+        Instruction::decode(&words)
+    }
 
-        values
+    fn jump_to(&mut self, to: u16) {
+        self.ip = to as usize;
     }
 
     fn push(&mut self, value: u16) {
@@ -363,8 +194,9 @@ impl State {
         self.instructions[&address]
     }
 
-    fn write_mem(&mut self, address: u16, value: u16) {
+    pub fn write_mem(&mut self, address: u16, value: u16) {
         self.instructions.insert(address, value);
+        self.writes += 1;
     }
 
     fn is_buffering_string(&self) -> bool {
@@ -377,11 +209,11 @@ impl State {
         let c = c.pop().unwrap();
         let c = c as u8;
 
-        if c == '\n' as u8 {
+        if c == b'\n' {
             self.text_buffer = None;
         }
 
-        return c as u16;
+        c as u16
     }
 
     fn start_buffering_string(&mut self, s: String) {
@@ -397,7 +229,7 @@ impl State {
 
         s.push_str("IP\n");
         s.push_str(&self.ip.to_string());
-        s.push_str("\n");
+        s.push('\n');
         s.push_str(&self.instructions[&(self.ip as u16)].to_string());
 
         s.push_str("\n\nRegisters\n");
@@ -413,189 +245,163 @@ impl State {
 
         eprintln!("Dumped");
     }
-}
 
-pub fn run_loop(instructions: Vec<u16>) {
-    let mut state = State::build(instructions);
-    let mut debugger = Debugger::build();
-
-    loop {
-        debugger.stats.record_instruction();
-        debugger.check_for_breakpoints(state.ip);
+    /// The value of an operand.
+    fn value(&self, value: Operand) -> u16 {
+        match value {
+            Operand::Literal(c) => c,
+            Operand::Register(r) => self.registers[r],
+            Operand::Invalid(word) => panic!("Invalid operand {}", word),
+        }
+    }
 
-        let instruction = Instruction::build(state.opcode(), &mut state);
+    /// Executes `instruction`, which was decoded from `ip`.
+    pub fn execute(&mut self, instruction: Instruction, debugger: &mut Debugger) -> Step {
+        let address = self.ip;
+        let effect = instruction
+            .lift()
+            .unwrap_or_else(|| panic!("Invalid instruction {} at {}", instruction.opcode, address));
+        self.ip += instruction.size();
 
-        if !state.is_buffering_string() {
-            debugger.maybe_present(&mut state, &instruction);
+        if let Some(shadow) = &mut self.shadow {
+            shadow.step(address, &effect, &self.registers);
         }
 
-        match instruction.opcode {
-            Opcode::Unknown => {
-                panic!("Unknown opcode {}", state.instructions[&(state.ip as u16)]);
+        match effect {
+            Effect::Halt => {
+                return Step::Halted;
             }
-            Opcode::Halt => {
-                break;
+            Effect::Assign(r, expr) => {
+                self.registers[r] = expr.evaluate(|v| Some(self.value(v))).expect("Modulo by zero");
             }
-            Opcode::Add => {
-                if let [a, b, c] = instruction.args.as_slice() {
-                    let result = ((*b as usize + *c as usize) % 32768) as u16;
-                    state.set_register(*a, result);
-                }
+            Effect::Load(r, from) => {
+                self.registers[r] = self.read_mem(self.value(from));
             }
-            Opcode::Mult => {
-                if let [a, b, c] = instruction.args.as_slice() {
-                    let result = ((*b as usize * *c as usize) % 32768) as u16;
-                    state.set_register(*a, result);
-                }
+            Effect::Store(to, value) => {
+                self.write_mem(self.value(to), self.value(value));
             }
-            Opcode::Mod => {
-                if let [a, b, c] = instruction.args.as_slice() {
-                    let result = *b % *c;
-                    state.set_register(*a, result);
-                }
+            Effect::Push(value) => {
+                self.push(self.value(value));
             }
-            Opcode::Push => {
-                if let [a] = instruction.args.as_slice() {
-                    state.push(*a);
-                }
+            Effect::Pop(r) => {
+                self.registers[r] = self.pop().unwrap();
             }
-            Opcode::Pop => {
-                if let [a] = instruction.args.as_slice() {
-                    let value = state.pop().unwrap();
-                    state.set_register(*a, value);
+            Effect::Jump(target) => {
+                let to = self.value(target);
+                if target.register().is_some() {
+                    debugger.stats.record_jump(address as u16, to);
                 }
+                self.jump_to(to);
             }
-            Opcode::Gt => {
-                if let [a, b, c] = instruction.args.as_slice() {
-                    if *b > *c {
-                        state.set_register(*a, 1);
-                    } else {
-                        state.set_register(*a, 0);
+            Effect::Branch { condition, nonzero, target } => {
+                if (self.value(condition) != 0) == nonzero {
+                    let to = self.value(target);
+                    if target.register().is_some() {
+                        debugger.stats.record_jump(address as u16, to);
                     }
+                    self.jump_to(to);
                 }
             }
-            Opcode::Eq => {
-                if let [a, b, c] = instruction.args.as_slice() {
-                    if *b == *c {
-                        state.set_register(*a, 1);
-                    } else {
-                        state.set_register(*a, 0);
-                    }
-                }
-            }
-            Opcode::And => {
-                if let [a, b, c] = instruction.args.as_slice() {
-                    state.set_register(*a, *b & *c);
-                }
-            }
-            Opcode::Or => {
-                if let [a, b, c] = instruction.args.as_slice() {
-                    state.set_register(*a, *b | *c);
-                }
-            }
-            Opcode::Not => {
-                if let [a, b] = instruction.args.as_slice() {
-                    state.set_register(*a, !*b & 32767);
-                }
-            }
-            Opcode::Set => {
-                if let [a, b] = instruction.args.as_slice() {
-                    // if *a == 32768 && *b == 26851 {
-                    //     debugger.enable();
-                    //     state.ip -= 3;
-                    //     continue;
-                    // }
-
-                    state.set_register(*a, *b);
-                }
+            Effect::Call(target) => {
+                let to = self.value(target);
+                debugger.stats.record_call(address as u16, to);
+                self.push(self.ip as u16);
+                self.call_stack.push(address as u16);
+                self.jump_to(to);
             }
-            Opcode::Jmp => {
-                if let [to] = instruction.args.as_slice() {
-                    state.jump_to(*to);
+            Effect::Ret => {
+                if let Some(target) = self.pop() {
+                    self.call_stack.pop().unwrap();
+                    self.jump_to(target);
+                } else {
+                    return Step::Halted;
                 }
             }
-            Opcode::JmpIfTrue => {
-                if let [a, b] = instruction.args.as_slice() {
-                    if *a != 0 {
-                        state.jump_to(*b);
+            Effect::In(r) => {
+                if !self.is_buffering_string() {
+                    let text = match self.input.pop_front() {
+                        Some(line) => line + "\n",
+                        None if self.interactive => {
+                            let mut text = String::new();
+                            std::io::stdin().read_line(&mut text).unwrap();
+                            text
+                        }
+                        None => {
+                            // Rewind so this `In` is replayed once there's input
+                            self.ip = address;
+                            return Step::AwaitingInput;
+                        }
+                    };
+
+                    if text == "dump\n" {
+                        self.dump();
+                        return Step::Running;
                     }
-                }
-            }
-            Opcode::JmpIfFalse => {
-                if let [a, b] = instruction.args.as_slice() {
-                    if *a == 0 {
-                        state.jump_to(*b);
+
+                    if text == "debug\n" {
+                        eprintln!("Starting debugger...");
+                        debugger.enable();
+                        // Rewind so this `In` is replayed
+                        self.ip = address;
+                        return Step::Running;
                     }
+
+                    self.start_buffering_string(text);
                 }
-            }
-            Opcode::Call => {
-                if let [a] = instruction.args.as_slice() {
-                    debugger.stats.record_call(*a);
-                    state.push(state.ip as u16);
-                    state.call_stack.push((state.ip - 2) as u16);
-                    state.jump_to(*a);
+
+                let value = self.text_buffer_char();
+                self.registers[r] = value;
+
+                if let Some(shadow) = &mut self.shadow {
+                    shadow.read(r, value);
                 }
             }
-            Opcode::RMem => {
-                if let [a, b] = instruction.args.as_slice() {
-                    state.set_register(*a, state.read_mem(*b));
+            Effect::Out(value) => {
+                let byte = self.value(value) as u8;
+                let char = byte as char;
+                self.output.push(char);
+
+                if char == '\n' && self.scrollback.is_some() {
+                    self.output_lines += 1;
+                    self.trim_output();
                 }
-            }
-            Opcode::WMem => {
-                if let [a, b] = instruction.args.as_slice() {
-                    state.write_mem(*a, *b);
+
+                if let Some(shadow) = &mut self.shadow {
+                    shadow.emit(address, &self.call_stack, value, byte as u16);
                 }
-            }
-            Opcode::Ret => {
-                if let Some(target) = state.pop() {
-                    state.call_stack.pop().unwrap();
-                    state.jump_to(target);
-                } else {
-                    break;
+
+                if self.echo {
+                    print!("{}", char);
                 }
             }
-            Opcode::In => {
-                if let [a] = instruction.args.as_slice() {
-                    if !state.is_buffering_string() {
-                        let mut text = String::new();
-                        std::io::stdin().read_line(&mut text).unwrap();
-
-                        if text == "dump\n" {
-                            state.dump();
-                            continue;
-                        }
+            Effect::Noop => {}
+        }
 
-                        if text == "debug\n" {
-                            eprintln!("Starting debugger...");
-                            debugger.enable();
-                            // Rewind so this `In` is replayed
-                            state.ip -= 2;
-                            continue;
-                        }
+        Step::Running
+    }
+}
 
-                        state.start_buffering_string(text);
-                    }
+pub fn run_loop(instructions: Vec<u16>, mut debugger: Debugger) {
+    let mut state = State::build(instructions);
+    if debugger.tui {
+        state.set_scrollback(Some(tui::SCROLLBACK));
+    }
 
-                    let value = state.text_buffer_char();
-                    state.set_register(*a, value);
-                }
-            }
-            Opcode::Out => {
-                if let [a] = instruction.args.as_slice() {
-                    // if !debugger.enabled && *a == 68 as u16 {
-                    //     debugger.enable();
-                    //     // state.ip -= 2;
-                    //     continue;
-                    // }
-
-                    let byte = *a as u8;
-                    let char = byte as char;
-                    print!("{}", char);
-                }
-            }
-            Opcode::Noop => {
-                state.jump_by(1);
-            }
+    loop {
+        debugger.stats.record_instruction();
+        debugger.check_for_breakpoints(state.ip);
+        debugger.record_ip(state.ip);
+
+        let instruction = state.instruction(state.ip).expect("Unknown opcode");
+
+        // The debugger moved `ip`, so carry on from there instead
+        if !state.is_buffering_string() && debugger.maybe_present(&mut state, &instruction) {
+            continue;
+        }
+
+        match state.execute(instruction, &mut debugger) {
+            Step::Running => (),
+            Step::Halted | Step::AwaitingInput => break,
         }
     }
 
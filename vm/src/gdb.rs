@@ -0,0 +1,430 @@
+//! A GDB Remote Serial Protocol stub, so `target remote :<port>` works against the VM.
+//!
+//! GDB thinks in bytes, so memory is exposed as a 64KiB byte space holding the
+//! 15-bit word address space in little-endian order, just like the .bin format.
+//! `pc` is a byte address too (`ip * 2`), and so are breakpoint addresses.
+
+use super::debug::{Debugger, Resume, Stop};
+use super::exec::State;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.synacor.cpu">
+    <reg name="r0" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="uint16"/>
+    <reg name="r2" bitsize="16" type="uint16"/>
+    <reg name="r3" bitsize="16" type="uint16"/>
+    <reg name="r4" bitsize="16" type="uint16"/>
+    <reg name="r5" bitsize="16" type="uint16"/>
+    <reg name="r6" bitsize="16" type="uint16"/>
+    <reg name="r7" bitsize="16" type="uint16"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// Index of `pc` in the `g` packet, after the eight general purpose registers.
+const PC: usize = 8;
+
+// The largest packet we accept or send, as advertised in `qSupported`.
+const PACKET_SIZE: usize = 0x4000;
+
+struct Session {
+    stream: TcpStream,
+    state: State,
+    debugger: Debugger,
+    ack: bool,
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn encode_word(value: u16) -> String {
+    format!("{:02x}{:02x}", value & 0xff, value >> 8)
+}
+
+fn decode_word(hex: &str) -> Option<u16> {
+    let low = u8::from_str_radix(hex.get(0..2)?, 16).ok()?;
+    let high = u8::from_str_radix(hex.get(2..4)?, 16).ok()?;
+    Some(((high as u16) << 8) | low as u16)
+}
+
+fn decode_bytes(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Parses the `addr,length` pair used by memory and qXfer packets.
+fn address_and_length(args: &str) -> Option<(usize, usize)> {
+    let (address, length) = args.split_once(',')?;
+    let address = usize::from_str_radix(address, 16).ok()?;
+    let length = usize::from_str_radix(length, 16).ok()?;
+    Some((address, length))
+}
+
+impl Session {
+    /// Reads one packet, acknowledging it, or asking for it again if its checksum is wrong.
+    /// Returns `None` once the client disconnects.
+    fn read_packet(&mut self) -> Option<String> {
+        let mut byte = [0u8; 1];
+
+        loop {
+            loop {
+                if self.stream.read(&mut byte).ok()? == 0 {
+                    return None;
+                }
+
+                match byte[0] {
+                    b'$' => break,
+                    // A stray interrupt while we're already stopped; report where we are.
+                    0x03 => return Some("?".to_owned()),
+                    _ => continue,
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                if self.stream.read(&mut byte).ok()? == 0 {
+                    return None;
+                }
+
+                match byte[0] {
+                    b'#' => break,
+                    b => data.push(b),
+                }
+            }
+
+            let mut sum = [0u8; 2];
+            self.stream.read_exact(&mut sum).ok()?;
+
+            // Without acks there's no way to ask for a resend, so checksums aren't checked
+            if self.ack {
+                let expected = std::str::from_utf8(&sum).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+                if expected != Some(checksum(&data)) {
+                    self.stream.write_all(b"-").ok()?;
+                    continue;
+                }
+                self.stream.write_all(b"+").ok()?;
+            }
+
+            return Some(String::from_utf8_lossy(&data).into_owned());
+        }
+    }
+
+    /// Sends one packet. Returns `None` if the client has gone.
+    fn send(&mut self, data: &str) -> Option<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes()).ok()
+    }
+
+    fn read_memory(&self, address: usize) -> u8 {
+        let word = self.state.instructions.get(&((address / 2) as u16)).copied().unwrap_or(0);
+
+        if address.is_multiple_of(2) {
+            (word & 0xff) as u8
+        } else {
+            (word >> 8) as u8
+        }
+    }
+
+    fn write_memory(&mut self, address: usize, byte: u8) {
+        let index = (address / 2) as u16;
+        let word = self.state.instructions.get(&index).copied().unwrap_or(0);
+
+        let word = if address.is_multiple_of(2) {
+            (word & 0xff00) | byte as u16
+        } else {
+            (word & 0x00ff) | ((byte as u16) << 8)
+        };
+
+        self.state.write_mem(index, word);
+    }
+
+    fn register(&self, index: usize) -> Option<u16> {
+        match index {
+            PC => Some((self.state.ip * 2) as u16),
+            i if i < PC => Some(self.state.registers[i]),
+            _ => None,
+        }
+    }
+
+    fn set_register(&mut self, index: usize, value: u16) -> bool {
+        match index {
+            PC => self.state.ip = value as usize / 2,
+            i if i < PC => self.state.registers[i] = value,
+            _ => return false,
+        }
+
+        true
+    }
+
+    fn resume(&mut self, resume: Resume) -> String {
+        let stream = &self.stream;
+        let mut interrupted = || {
+            let mut byte = [0u8; 1];
+            stream.set_nonblocking(true).unwrap();
+            let interrupted = match (&*stream).read(&mut byte) {
+                Ok(1) => byte[0] == 0x03,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => false,
+                _ => true,
+            };
+            stream.set_nonblocking(false).unwrap();
+            interrupted
+        };
+
+        match self.debugger.resume(&mut self.state, resume, &mut interrupted) {
+            Stop::Step | Stop::Breakpoint | Stop::AwaitingInput => "S05".to_owned(),
+            Stop::Interrupted => "S02".to_owned(),
+            Stop::InvalidOpcode => "S04".to_owned(),
+            Stop::Halted => "W00".to_owned(),
+        }
+    }
+
+    /// Handles a single packet, returning the reply (`None` ends the session).
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let (command, args) = packet.split_at(packet.len().min(1));
+
+        let reply = match command {
+            "?" => "S05".to_owned(),
+            "g" => (0..=PC)
+                .map(|i| encode_word(self.register(i).unwrap()))
+                .collect(),
+            "G" => {
+                for i in 0..=PC {
+                    if let Some(value) = args.get(i * 4..i * 4 + 4).and_then(decode_word) {
+                        self.set_register(i, value);
+                    }
+                }
+                "OK".to_owned()
+            }
+            "p" => match usize::from_str_radix(args, 16).ok().and_then(|i| self.register(i)) {
+                Some(value) => encode_word(value),
+                None => "E01".to_owned(),
+            },
+            "P" => {
+                let set = args.split_once('=').and_then(|(index, value)| {
+                    let index = usize::from_str_radix(index, 16).ok()?;
+                    let value = decode_word(value)?;
+                    Some(self.set_register(index, value))
+                });
+
+                match set {
+                    Some(true) => "OK".to_owned(),
+                    _ => "E01".to_owned(),
+                }
+            }
+            "m" => match address_and_length(args) {
+                // Two hex digits per byte have to fit in the reply
+                Some((address, length)) => (address % 0x10000..address % 0x10000 + length.min(PACKET_SIZE / 2))
+                    .map(|a| format!("{:02x}", self.read_memory(a % 0x10000)))
+                    .collect(),
+                None => "E01".to_owned(),
+            },
+            "M" => {
+                let written = args.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = address_and_length(range)?;
+                    let bytes = decode_bytes(data)?;
+                    for (i, byte) in bytes.into_iter().take(length).enumerate() {
+                        self.write_memory((address % 0x10000 + i) % 0x10000, byte);
+                    }
+                    Some(())
+                });
+
+                match written {
+                    Some(()) => "OK".to_owned(),
+                    None => "E01".to_owned(),
+                }
+            }
+            "Z" | "z" => {
+                let mut parts = args.split(',');
+                let kind = parts.next();
+                let address = parts.next().and_then(|a| usize::from_str_radix(a, 16).ok());
+
+                match (kind, address) {
+                    (Some("0"), Some(address)) | (Some("1"), Some(address)) => {
+                        let address = (address / 2) as u16;
+                        if command == "Z" {
+                            self.debugger.breakpoints.insert(address);
+                        } else {
+                            self.debugger.breakpoints.remove(&address);
+                        }
+                        "OK".to_owned()
+                    }
+                    _ => "".to_owned(),
+                }
+            }
+            "s" | "c" => {
+                if let Ok(address) = usize::from_str_radix(args, 16) {
+                    self.state.ip = address / 2;
+                }
+
+                if command == "s" {
+                    self.resume(Resume::Step)
+                } else {
+                    self.resume(Resume::Continue)
+                }
+            }
+            "H" => "OK".to_owned(),
+            "k" => return None,
+            "D" => {
+                self.send("OK")?;
+                return None;
+            }
+            _ => self.query(packet),
+        };
+
+        Some(reply)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE)
+        } else if packet == "QStartNoAckMode" {
+            // The packet itself has already been acked, so this takes effect from the next one.
+            self.ack = false;
+            "OK".to_owned()
+        } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            match address_and_length(range) {
+                Some((offset, length)) if offset < TARGET_XML.len() => {
+                    let end = offset.saturating_add(length).min(TARGET_XML.len());
+                    let prefix = if end == TARGET_XML.len() { "l" } else { "m" };
+                    format!("{}{}", prefix, &TARGET_XML[offset..end])
+                }
+                Some(_) => "l".to_owned(),
+                None => "E01".to_owned(),
+            }
+        } else {
+            match packet {
+                "qAttached" => "1".to_owned(),
+                "qC" => "QC1".to_owned(),
+                "qfThreadInfo" => "m1".to_owned(),
+                "qsThreadInfo" => "l".to_owned(),
+                _ => "".to_owned(),
+            }
+        }
+    }
+}
+
+/// Waits for a single GDB connection on `127.0.0.1:port` and serves it until it detaches.
+pub fn serve(instructions: Vec<u16>, port: u16) {
+    let listener = TcpListener::bind(("127.0.0.1", port)).expect("Failed to bind GDB port");
+    eprintln!("Waiting for GDB on 127.0.0.1:{}...", port);
+
+    accept(listener, instructions);
+}
+
+fn accept(listener: TcpListener, instructions: Vec<u16>) {
+    let (stream, address) = listener.accept().expect("Failed to accept GDB connection");
+    eprintln!("GDB connected from {}", address);
+
+    let mut session = Session {
+        stream,
+        state: State::build(instructions),
+        debugger: Debugger::build(),
+        ack: true,
+    };
+
+    while let Some(packet) = session.read_packet() {
+        match session.handle(&packet) {
+            Some(reply) if session.send(&reply).is_some() => {}
+            _ => break,
+        }
+    }
+
+    eprintln!("GDB session ended");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    const PROGRAM: &str = "
+        set r0 5
+        add r1 r0 2
+        noop
+        halt
+    ";
+
+    struct Client(TcpStream);
+
+    impl Client {
+        fn connect() -> Client {
+            let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+            let address = listener.local_addr().unwrap();
+            let program = asm::assemble(PROGRAM).ok().unwrap();
+            std::thread::spawn(move || accept(listener, program));
+            Client(TcpStream::connect(address).unwrap())
+        }
+
+        fn read_byte(&mut self) -> u8 {
+            let mut byte = [0u8; 1];
+            self.0.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn send_raw(&mut self, packet: &str) -> u8 {
+            self.0.write_all(packet.as_bytes()).unwrap();
+            self.read_byte()
+        }
+
+        /// Sends a packet and returns the reply, checking both are acknowledged.
+        fn request(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+            assert_eq!(self.send_raw(&packet), b'+');
+
+            assert_eq!(self.read_byte(), b'$');
+            let mut reply = Vec::new();
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    b => reply.push(b),
+                }
+            }
+            let sum = [self.read_byte(), self.read_byte()];
+            assert_eq!(std::str::from_utf8(&sum).unwrap(), format!("{:02x}", checksum(&reply)));
+            self.0.write_all(b"+").unwrap();
+
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    #[test]
+    fn runs_to_a_breakpoint() {
+        let mut client = Client::connect();
+
+        assert!(client.request("qSupported:swbreak+").contains("PacketSize=4000"));
+        assert_eq!(client.request("g"), "000000000000000000000000000000000000");
+        // set r0 5
+        assert_eq!(client.request("m0,6"), "010000800500");
+        // The noop is at word 7
+        assert_eq!(client.request("Z0,e,2"), "OK");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("g"), "050007000000000000000000000000000e00");
+        assert_eq!(client.request("c"), "W00");
+    }
+
+    #[test]
+    fn rejects_bad_packets() {
+        let mut client = Client::connect();
+
+        assert_eq!(client.send_raw("$g#00"), b'-');
+        assert_eq!(client.request("m0,ffff").len(), PACKET_SIZE);
+        assert_eq!(client.request("M0,2:0\u{e9}0"), "E01");
+        assert_eq!(client.request("M0,1:0"), "E01");
+        assert_eq!(client.request("M0,1:ff"), "OK");
+        // Wraps around to the start of memory, written just above
+        assert_eq!(client.request("mffffffffffffffff,4"), "00ff0000");
+        assert_eq!(client.request("Mffffffffffffffff,1:01"), "OK");
+        assert_eq!(client.request("qXfer:features:read:target.xml:0,ffffffffffffffff").len(), TARGET_XML.len() + 1);
+    }
+}
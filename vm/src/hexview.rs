@@ -0,0 +1,120 @@
+//! A hexdump-style memory renderer shared by the debugger, the TUI and `vm hexdump`.
+
+use super::util;
+use std::collections::HashMap;
+
+const HIGHLIGHT: &str = "\x1b[7m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Clone, Copy)]
+pub enum Radix {
+    Hex,
+    Decimal,
+}
+
+#[derive(Clone)]
+pub struct HexView {
+    pub columns: usize,
+    pub radix: Radix,
+    /// Whether invalid words are highlighted with ANSI escapes (they're always marked with `!`).
+    pub color: bool,
+}
+
+impl HexView {
+    pub fn new() -> HexView {
+        HexView {
+            columns: 8,
+            radix: Radix::Hex,
+            color: true,
+        }
+    }
+
+    pub fn toggle_radix(&mut self) {
+        self.radix = match self.radix {
+            Radix::Hex => Radix::Decimal,
+            Radix::Decimal => Radix::Hex,
+        };
+    }
+
+    pub fn toggle_width(&mut self) {
+        self.columns = if self.columns == 8 { 16 } else { 8 };
+    }
+
+    fn cell_width(&self) -> usize {
+        match self.radix {
+            Radix::Hex => 4,
+            Radix::Decimal => 5,
+        }
+    }
+
+    fn cell(&self, word: Option<u16>) -> String {
+        let width = self.cell_width();
+
+        let text = match word {
+            None => " ".repeat(width),
+            Some(w @ 32768..=32775) => format!("{:>width$}", util::register_pretty(&w), width = width),
+            Some(w) => match self.radix {
+                Radix::Hex => format!("{:04x}", w),
+                Radix::Decimal => format!("{:5}", w),
+            },
+        };
+
+        match word {
+            Some(w) if w > 32775 && self.color => format!("!{}{}{}", HIGHLIGHT, text, RESET),
+            Some(w) if w > 32775 => format!("!{}", text),
+            _ => format!(" {}", text),
+        }
+    }
+
+    fn ascii(word: Option<u16>) -> char {
+        match word.and_then(util::to_ascii) {
+            Some(c) if !c.is_control() => c,
+            Some(_) => '.',
+            None if word.is_some() => '.',
+            None => ' ',
+        }
+    }
+
+    /// Renders `count` words starting at `start`, a row at a time. Rows are aligned to
+    /// the column count, and any labels falling inside a row are listed just above it.
+    pub fn render(
+        &self,
+        memory: &[u16],
+        start: usize,
+        count: usize,
+        labels: &HashMap<usize, String>,
+    ) -> Vec<String> {
+        let end = start + count;
+        let mut row = start - start % self.columns;
+        let mut lines = Vec::new();
+
+        while row < end {
+            let addresses = row..row + self.columns;
+            let visible = |a: &usize| (start..end).contains(a);
+
+            let mut row_labels: Vec<_> = addresses
+                .clone()
+                .filter(visible)
+                .filter_map(|a| labels.get(&a).map(|l| (a, l)))
+                .collect();
+            row_labels.sort();
+
+            for (address, label) in row_labels {
+                lines.push(format!("       ; {}: {}", address, label));
+            }
+
+            let words: Vec<_> = addresses
+                .clone()
+                .map(|a| if visible(&a) { memory.get(a).copied() } else { None })
+                .collect();
+
+            let cells: String = words.iter().map(|w| self.cell(*w)).collect();
+            let ascii: String = words.iter().map(|w| Self::ascii(*w)).collect();
+
+            lines.push(format!("{:5}:{}  |{}|", row, cells, ascii));
+            row += self.columns;
+        }
+
+        lines
+    }
+}
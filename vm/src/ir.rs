@@ -0,0 +1,160 @@
+//! A lifted form of each instruction, spelling out the registers, memory and stack it reads
+//! and writes and where control goes next. The interpreter, the data-flow analyses and the
+//! symbolic executor all work from this, so the semantics of each opcode live here once.
+
+use super::dataflow::Registers;
+use super::opcode::{Opcode, Operand};
+
+/// The result of one of the three-operand arithmetic or comparison opcodes, or `None` for a
+/// modulo by zero.
+pub fn apply(opcode: Opcode, a: u16, b: u16) -> Option<u16> {
+    let (a, b) = (a as u32, b as u32);
+    let result = match opcode {
+        Opcode::Add => (a + b) % 32768,
+        Opcode::Mult => (a * b) % 32768,
+        Opcode::Mod if b == 0 => return None,
+        Opcode::Mod => a % b,
+        Opcode::Eq => (a == b) as u32,
+        Opcode::Gt => (a > b) as u32,
+        Opcode::And => a & b,
+        Opcode::Or => a | b,
+        _ => unreachable!("{} isn't a binary operation", opcode),
+    };
+    Some(result as u16)
+}
+
+pub fn not(a: u16) -> u16 {
+    !a & 32767
+}
+
+/// What's computed into a register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Expr {
+    Copy(Operand),
+    Binary(Opcode, Operand, Operand),
+    Not(Operand),
+}
+
+impl Expr {
+    pub fn operands(&self) -> Vec<Operand> {
+        match *self {
+            Expr::Copy(a) | Expr::Not(a) => vec![a],
+            Expr::Binary(_, a, b) => vec![a, b],
+        }
+    }
+
+    /// The result given `value` for each operand, or `None` if an operand has no value or
+    /// it's a modulo by zero.
+    pub fn evaluate(&self, value: impl Fn(Operand) -> Option<u16>) -> Option<u16> {
+        match *self {
+            Expr::Copy(a) => value(a),
+            Expr::Binary(opcode, a, b) => apply(opcode, value(a)?, value(b)?),
+            Expr::Not(a) => value(a).map(not),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Effect {
+    Assign(usize, Expr),
+    /// `register = mem[address]`
+    Load(usize, Operand),
+    /// `mem[address] = value`
+    Store(Operand, Operand),
+    Push(Operand),
+    /// Pops into the register.
+    Pop(usize),
+    Jump(Operand),
+    /// Jumps to `target` if `condition` is nonzero, or zero if `nonzero` is false.
+    Branch { condition: Operand, nonzero: bool, target: Operand },
+    /// Pushes the address of the next instruction and jumps.
+    Call(Operand),
+    /// Pops an address and jumps to it, or halts if the stack is empty.
+    Ret,
+    Halt,
+    Out(Operand),
+    /// Reads a character into the register.
+    In(usize),
+    Noop,
+}
+
+/// The effect of `opcode` with `operands`, or `None` if it isn't a known opcode with the
+/// right number of operands, or it writes to something other than a register.
+pub fn lift(opcode: Opcode, operands: &[Operand]) -> Option<Effect> {
+    if opcode == Opcode::Unknown || operands.len() != opcode.arg_count() {
+        return None;
+    }
+
+    let value = |i: usize| operands[i];
+    let destination = || operands[0].register();
+
+    let effect = match opcode {
+        Opcode::Halt => Effect::Halt,
+        Opcode::Set => Effect::Assign(destination()?, Expr::Copy(value(1))),
+        Opcode::Add | Opcode::Mult | Opcode::Mod | Opcode::Eq | Opcode::Gt | Opcode::And | Opcode::Or => {
+            Effect::Assign(destination()?, Expr::Binary(opcode, value(1), value(2)))
+        }
+        Opcode::Not => Effect::Assign(destination()?, Expr::Not(value(1))),
+        Opcode::Push => Effect::Push(value(0)),
+        Opcode::Pop => Effect::Pop(destination()?),
+        Opcode::RMem => Effect::Load(destination()?, value(1)),
+        Opcode::WMem => Effect::Store(value(0), value(1)),
+        Opcode::Jmp => Effect::Jump(value(0)),
+        Opcode::JmpIfTrue | Opcode::JmpIfFalse => Effect::Branch {
+            condition: value(0),
+            nonzero: opcode == Opcode::JmpIfTrue,
+            target: value(1),
+        },
+        Opcode::Call => Effect::Call(value(0)),
+        Opcode::Ret => Effect::Ret,
+        Opcode::Out => Effect::Out(value(0)),
+        Opcode::In => Effect::In(destination()?),
+        Opcode::Noop => Effect::Noop,
+        Opcode::Unknown => unreachable!(),
+    };
+
+    Some(effect)
+}
+
+impl Effect {
+    /// Every operand that's read, including addresses and targets.
+    pub fn operands(&self) -> Vec<Operand> {
+        match *self {
+            Effect::Assign(_, expr) => expr.operands(),
+            Effect::Load(_, a) | Effect::Push(a) | Effect::Jump(a) | Effect::Call(a) | Effect::Out(a) => vec![a],
+            Effect::Store(a, b) => vec![a, b],
+            Effect::Branch { condition, target, .. } => vec![condition, target],
+            Effect::Pop(_) | Effect::Ret | Effect::Halt | Effect::In(_) | Effect::Noop => vec![],
+        }
+    }
+
+    /// The registers read as operands.
+    pub fn reads(&self) -> Registers {
+        self.operands().iter().filter_map(|v| v.register()).fold(0, |set, r| set | 1 << r)
+    }
+
+    /// The register written, if any.
+    pub fn destination(&self) -> Option<usize> {
+        match *self {
+            Effect::Assign(r, _) | Effect::Load(r, _) | Effect::Pop(r) | Effect::In(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    pub fn writes(&self) -> Registers {
+        self.destination().map_or(0, |r| 1 << r)
+    }
+
+    /// The jump or call target.
+    pub fn target(&self) -> Option<Operand> {
+        match *self {
+            Effect::Jump(target) | Effect::Call(target) | Effect::Branch { target, .. } => Some(target),
+            _ => None,
+        }
+    }
+
+    /// Whether execution can continue with the next instruction in memory.
+    pub fn falls_through(&self) -> bool {
+        !matches!(self, Effect::Halt | Effect::Ret | Effect::Jump(_))
+    }
+}
@@ -1,17 +1,577 @@
+mod asm;
 mod build;
+mod callgraph;
+mod cfg;
+mod constprop;
+mod convention;
+mod dap;
+mod dataflow;
 mod debug;
+mod decompile;
 mod exec;
+mod ir;
 mod opcode;
+pub mod search;
+mod stackdepth;
+mod strings;
+mod symbolic;
+mod symbols;
+mod taint;
 mod util;
-mod disasm;
+mod verify;
+pub mod disasm;
+mod gdb;
+mod hexview;
+mod library;
+mod tui;
+pub mod xref;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 pub fn start(filename: &str) {
     let instructions = build::read_binary(filename);
     // println!("{:?}", instructions);
-    exec::run_loop(instructions);
+    exec::run_loop(instructions, debug::Debugger::build());
+}
+
+/// Runs `filename` with the full-screen debugger, stopped at the first instruction.
+pub fn start_tui(filename: &str) {
+    let instructions = build::read_binary(filename);
+    let mut debugger = debug::Debugger::build();
+    debugger.tui = true;
+    debugger.enable();
+    exec::run_loop(instructions, debugger);
 }
 
-pub fn export(filename: &str) {
+/// Serves `filename` to a GDB client connecting to `127.0.0.1:port`.
+pub fn start_gdb(filename: &str, port: u16) {
     let instructions = build::read_binary(filename);
-    disasm::disassemble_instructions(instructions);
+    gdb::serve(instructions, port);
+}
+
+/// Serves a Debug Adapter Protocol session on stdin/stdout.
+pub fn start_dap() {
+    dap::serve();
+}
+
+/// Prints a hexdump of `count` words at `start`, from a .bin file or a `dump` snapshot.
+pub fn hexdump(filename: &str, start: usize, count: usize, decimal: bool, wide: bool) {
+    let memory = build::read_memory(filename);
+    let mut view = hexview::HexView::new();
+
+    if decimal {
+        view.toggle_radix();
+    }
+    if wide {
+        view.toggle_width();
+    }
+
+    for line in view.render(&memory, start, count, &symbols::labels()) {
+        println!("{}", line);
+    }
+}
+
+/// Where the static tools take memory from.
+pub enum Source<'a> {
+    /// The image on disk.
+    Binary,
+    /// Memory at the first `In`, once the program has finished decrypting itself.
+    AfterBoot,
+    /// A snapshot written by the `dump` command.
+    Snapshot(&'a str),
+}
+
+/// Memory to analyse, along with the image it came from, addresses known to be code and
+/// where execution had got to.
+struct Image {
+    original: Vec<u16>,
+    memory: Vec<u16>,
+    entries: Vec<usize>,
+    ip: usize,
+    registers: Vec<u16>,
+    stack: Vec<u16>,
+}
+
+impl Image {
+    fn load(filename: &str, source: &Source) -> Image {
+        let original = build::read_binary(filename);
+
+        let (memory, ip, registers, stack) = match source {
+            Source::Binary => return Image {
+                memory: original.clone(),
+                original,
+                entries: vec![0],
+                ip: 0,
+                registers: vec![0; 8],
+                stack: Vec::new(),
+            },
+            Source::AfterBoot => {
+                let mut state = exec::State::build(original.clone());
+                state.echo = false;
+                state.interactive = false;
+
+                debug::Debugger::build().resume(&mut state, debug::Resume::Continue, &mut || false);
+                (state.memory(), state.ip, state.registers, state.stack)
+            }
+            Source::Snapshot(snapshot) => {
+                let snapshot = build::read_snapshot(snapshot).unwrap_or_else(|error| {
+                    eprintln!("{}", error);
+                    std::process::exit(1);
+                });
+                (snapshot.memory, snapshot.ip, snapshot.registers, snapshot.stack)
+            }
+        };
+
+        // Anything on the stack just after a `Call` is a return address, so it's code
+        let returns = stack
+            .iter()
+            .map(|&v| v as usize)
+            .filter(|&v| v >= 2 && memory.get(v - 2) == Some(&17));
+
+        let mut entries: Vec<_> = vec![0, ip].into_iter().chain(returns).collect();
+        entries.sort_unstable();
+        entries.dedup();
+
+        Image {
+            original,
+            memory,
+            entries,
+            ip,
+            registers,
+            stack,
+        }
+    }
+
+    /// The image's entry points, along with routines only ever called through a register
+    /// and anything else indirect calls and jumps are worked out to reach.
+    fn code_entries(&self, labels: &HashMap<usize, String>) -> Vec<usize> {
+        let analysis = disasm::recursive_descent(&self.memory, &self.entries);
+        let routines = strings::routines(&analysis, labels);
+        let entries = self.entries.iter().copied().chain(routines).collect();
+
+        self.resolve_entries(entries, labels)
+    }
+
+    /// `entries` along with whatever indirect calls and jumps from them are worked out to reach.
+    fn resolve_entries(&self, mut entries: Vec<usize>, labels: &HashMap<usize, String>) -> Vec<usize> {
+        loop {
+            let analysis = disasm::recursive_descent(&self.memory, &entries);
+            let graph = callgraph::CallGraph::resolved(&analysis, &entries, labels, None);
+            let found: BTreeSet<usize> = graph.targets.values().flatten().copied().collect();
+            let new: Vec<usize> = found.into_iter().filter(|t| !analysis.is_code(*t)).collect();
+
+            if new.is_empty() {
+                return entries;
+            }
+            entries.extend(new);
+        }
+    }
+
+    fn analyse(&self, labels: &HashMap<usize, String>) -> disasm::Analysis {
+        disasm::recursive_descent(&self.memory, &self.code_entries(labels))
+    }
+
+    /// The hand-written labels, plus one for each routine the built-in signature library
+    /// finds in memory.
+    fn labels(&self) -> HashMap<usize, String> {
+        let mut labels = symbols::labels();
+        library::Library::builtin().apply(&self.memory, &mut labels);
+        labels
+    }
+
+    /// Each function's calling convention: the library's where it gives one, otherwise
+    /// inferred from how the function uses registers.
+    fn signatures(&self, analysis: &disasm::Analysis, graph: &callgraph::CallGraph) -> BTreeMap<usize, convention::Signature> {
+        let mut signatures = convention::infer(analysis, graph);
+        let declared = library::Library::builtin().apply(&self.memory, &mut HashMap::new());
+        signatures.extend(declared.into_iter().filter(|(a, _)| graph.functions.contains_key(a)));
+        signatures
+    }
+
+    fn is_modified(&self) -> bool {
+        self.memory != self.original
+    }
+}
+
+/// Prints a disassembly: a linear sweep of the image, or a recursive descent if asked for or
+/// once memory has changed. `canonical` gives the syntax `vm asm` reassembles.
+pub fn export(filename: &str, recursive: bool, canonical: bool, source: Source) {
+    let image = Image::load(filename, &source);
+
+    if recursive || canonical || image.is_modified() {
+        let labels = image.labels();
+        let entries = image.code_entries(&labels);
+        let analysis = disasm::recursive_descent(&image.memory, &entries);
+        let strings = strings::scan(&image.memory, &strings::keys(&analysis, &labels));
+        let comments = strings::calls(&image.memory, &analysis, &labels);
+        let graph = callgraph::CallGraph::resolved(&analysis, &entries, &labels, None);
+        let signatures = image.signatures(&analysis, &graph);
+        let annotations = disasm::Annotations {
+            labels: &labels,
+            original: Some(&image.original).filter(|_| image.is_modified()).map(|o| o.as_slice()),
+            xrefs: None,
+            strings: Some(&strings),
+            comments: Some(&comments),
+            signatures: Some(&signatures),
+            targets: Some(&graph.targets),
+        };
+        disasm::disassemble_recursive(&image.memory, &entries, &annotations, canonical);
+    } else {
+        disasm::disassemble_instructions(image.memory);
+    }
+}
+
+/// Prints the call graph as Graphviz source, or as JSON with `json`. Indirect calls get an
+/// edge for each target constant propagation works out, and a profile saved with the
+/// debugger's `pstat` command adds call counts and the targets seen at runtime.
+pub fn callgraph(filename: &str, source: Source, profile: Option<&str>, json: bool) {
+    let image = Image::load(filename, &source);
+    let labels = image.labels();
+    let profile = profile.map(debug::Statistics::load);
+
+    let mut entries = image.code_entries(&labels);
+    if let Some(profile) = &profile {
+        entries.extend(profile.calls.keys().map(|&t| t as usize));
+        entries.extend(profile.jumps.values().flat_map(|t| t.keys()).map(|&t| t as usize));
+    }
+
+    let analysis = disasm::recursive_descent(&image.memory, &entries);
+    let graph = callgraph::CallGraph::resolved(&analysis, &entries, &labels, profile);
+
+    if json {
+        println!("{:#}", graph.json(&analysis));
+    } else {
+        print!("{}", graph.dot(&analysis));
+    }
+}
+
+/// Prints the basic-block control flow graph of the function at `entry` as Graphviz source.
+pub fn cfg(filename: &str, entry: usize, source: Source) {
+    let image = Image::load(filename, &source);
+    let labels = image.labels();
+
+    let mut entries = image.entries.clone();
+    entries.push(entry);
+
+    let analysis = disasm::recursive_descent(&image.memory, &entries);
+    let graph = callgraph::CallGraph::build(&analysis, &[0, entry], &labels, None);
+    let function = graph.functions.get(&entry).expect("No function at that address");
+
+    print!("{}", cfg::Cfg::build(&analysis, function).dot(&analysis, &labels));
+}
+
+/// Lists the length-prefixed strings in memory, decoding obfuscated ones with any keys
+/// the program is seen to pass to the decoder.
+pub fn strings(filename: &str, source: Source) {
+    let image = Image::load(filename, &source);
+    let labels = image.labels();
+    let keys = strings::keys(&image.analyse(&labels), &labels);
+
+    println!("; keys: {:?}", keys);
+    for found in strings::scan(&image.memory, &keys).iter() {
+        let key = found.key.map(|k| k.to_string()).unwrap_or_else(|| "-".to_owned());
+        println!("{:5} {:4} {:>5} {:?}", found.address, found.len(), key, found.text);
+    }
+}
+
+/// Assembles `input` into a .bin file at `output`, exiting with the errors if there are any.
+pub fn assemble(input: &str, output: &str) {
+    let source = std::fs::read_to_string(input).expect("Failed to read assembly file");
+
+    match asm::assemble(&source) {
+        Ok(image) => {
+            build::write_binary(output, &image);
+            eprintln!("Wrote {} words to {}", image.len(), output);
+        }
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}: {}", input, error);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Disassembles `memory` canonically and reassembles it, returning the first address that
+/// came back different.
+fn reassemble(memory: &[u16], entries: &[usize]) -> Result<(), String> {
+    let analysis = disasm::recursive_descent(memory, entries);
+    let xrefs = xref::Xrefs::build(&analysis);
+    let labels = HashMap::new();
+    let annotations = disasm::Annotations {
+        labels: &labels,
+        original: None,
+        xrefs: Some(&xrefs),
+        strings: None,
+        comments: None,
+        signatures: None,
+        targets: None,
+    };
+
+    // Every instruction decodes and encodes back to the same words
+    for address in 0..memory.len() {
+        if let Some(instruction) = exec::Instruction::decode(&memory[address..]) {
+            if instruction.encode() != memory[address..address + instruction.size()] {
+                return Err(format!("instruction at {} encodes differently", address));
+            }
+        }
+    }
+
+    let source = disasm::canonical(memory, &analysis, &annotations).join("\n");
+    let image = asm::assemble(&source).map_err(|errors| errors[0].to_string())?;
+
+    match (0..memory.len().max(image.len())).find(|&a| memory.get(a) != image.get(a)) {
+        Some(address) => Err(format!("differs at {}", address)),
+        None => Ok(()),
+    }
+}
+
+/// Checks that `vm asm` turns the canonical disassembly of `filename`, both as loaded and
+/// after boot, back into exactly the same words.
+pub fn roundtrip(filename: &str) {
+    let mut failures = 0;
+
+    for source in [Source::Binary, Source::AfterBoot] {
+        let image = Image::load(filename, &source);
+        let entries = image.code_entries(&symbols::labels());
+        if let Err(error) = reassemble(&image.memory, &entries) {
+            eprintln!("{}: {}", filename, error);
+            failures += 1;
+        }
+    }
+
+    println!("{} of 2 images round-tripped", 2 - failures);
+    if failures > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// Prints structured pseudocode for the function at `entry`, or for every function.
+pub fn decompile(filename: &str, entry: Option<usize>, source: Source) {
+    let image = Image::load(filename, &source);
+    let labels = image.labels();
+
+    let mut entries = image.code_entries(&labels);
+    entries.extend(entry);
+
+    let analysis = disasm::recursive_descent(&image.memory, &entries);
+    let starts: Vec<_> = std::iter::once(0).chain(entry).collect();
+    let graph = callgraph::CallGraph::build(&analysis, &starts, &labels, None);
+    let signatures = image.signatures(&analysis, &graph);
+
+    let functions: Vec<_> = match entry {
+        Some(entry) => vec![graph.functions.get(&entry).expect("No function at that address")],
+        None => graph.functions.values().collect(),
+    };
+
+    for function in functions {
+        for line in decompile::decompile(&analysis, function, &signatures, &labels) {
+            println!("{}", line);
+        }
+        println!();
+    }
+}
+
+/// Symbolically executes from `from` (or wherever the image had got to) with `symbols`
+/// unknown, printing the constraints on each path that reaches `target` and values that
+/// satisfy them.
+pub fn reach(
+    filename: &str,
+    target: usize,
+    from: Option<usize>,
+    symbols: &[&str],
+    steps: usize,
+    source: Source,
+) {
+    let image = Image::load(filename, &source);
+    let symbolic_input = symbols.contains(&"input");
+    let symbols: Vec<_> = symbols
+        .iter()
+        .filter(|s| **s != "input")
+        .map(|s| symbolic::Symbol::parse(s).unwrap_or_else(|| panic!("Invalid symbol {}", s)))
+        .collect();
+
+    let mut registers = [0; 8];
+    registers.copy_from_slice(&image.registers);
+    let start = symbolic::Start {
+        memory: &image.memory,
+        ip: from.unwrap_or(image.ip),
+        registers,
+        stack: &image.stack,
+    };
+    let limits = symbolic::Limits { steps, reached: 10 };
+    let report = symbolic::explore(&start, &symbols, symbolic_input, target, &limits);
+
+    for (i, path) in report.reached.iter().enumerate() {
+        println!("path {}: reaches {}", i + 1, target);
+        for constraint in &path.constraints {
+            println!("    {}", constraint);
+        }
+        if !path.output.is_empty() {
+            println!("    output {:?}", path.output);
+        }
+
+        let solutions = symbolic::solve(&path.constraints, &path.domains, 10_000_000, 32768);
+        match (solutions.symbols.as_slice(), solutions.values.first()) {
+            (_, None) if solutions.complete => println!("    no solution"),
+            (_, None) => println!("    no solution found within the search budget"),
+            ([], Some(_)) => println!("    always taken"),
+            ([symbol], Some(_)) => {
+                let values: Vec<_> = solutions.values.iter().map(|v| v[0]).collect();
+                let more = if solutions.complete { "" } else { ", ..." };
+                println!("    {}: {}{} ({} values)", symbol, util::ranges(values.iter().map(|&v| v as usize)), more, values.len());
+            }
+            (symbols, Some(values)) => {
+                let assignment: Vec<_> = symbols
+                    .iter()
+                    .zip(values)
+                    .map(|(s, &v)| format!("{} = {}", s, s.describe(v)))
+                    .collect();
+                println!("    e.g. {}", assignment.join(", "));
+            }
+        }
+    }
+
+    let ended: Vec<_> = report.ended.iter().map(|(reason, n)| format!("{} {}", n, reason)).collect();
+    println!("; {} path(s) reached {} in {} steps", report.reached.len(), target, report.steps);
+    if !ended.is_empty() {
+        println!("; others ended at: {}", ended.join(", "));
+    }
+}
+
+/// Plays `filename` with the lines of `input` as commands, tracking which input characters
+/// each value is computed from, then lists the code that compares or branches on input.
+pub fn taint(filename: &str, input: &str) {
+    let mut state = exec::State::build(build::read_binary(filename));
+    state.echo = false;
+    state.interactive = false;
+    state.input = std::fs::read_to_string(input)
+        .expect("Failed to read input file")
+        .lines()
+        .map(str::to_owned)
+        .collect();
+    state.shadow = Some(taint::Shadow::new());
+
+    debug::Debugger::build().resume(&mut state, debug::Resume::Continue, &mut || false);
+
+    let shadow = state.shadow.as_ref().unwrap();
+    println!("; {} characters of input, {} words of memory tainted", shadow.text.len(), shadow.tainted_words());
+    for line in shadow.report(&state.memory(), &symbols::labels()) {
+        println!("{}", line);
+    }
+}
+
+/// Lists each function's net effect on the stack and deepest push, along with anything that
+/// doesn't balance: paths meeting at different depths, returns with more or less than the
+/// return address on top, and pops that take the return address as data.
+pub fn stack(filename: &str, source: Source) {
+    let image = Image::load(filename, &source);
+    let labels = image.labels();
+    let entries = image.code_entries(&labels);
+    let analysis = disasm::recursive_descent(&image.memory, &entries);
+    let graph = callgraph::CallGraph::resolved(&analysis, &entries, &labels, None);
+    let depths = stackdepth::analyse(&analysis, &graph);
+
+    let unbalanced = depths.values().filter(|d| !d.is_balanced()).count();
+    println!("; {} functions, {} unbalanced", depths.len(), unbalanced);
+
+    for (entry, depths) in &depths {
+        let effect = match depths.effect {
+            Some(effect) => format!("{:+}", effect),
+            None => "?".to_owned(),
+        };
+        println!("{:5} {:<24} effect {:>3}  max {:>2}", entry, graph.functions[entry].name, effect, depths.max);
+
+        for problem in &depths.problems {
+            println!("      {}", problem);
+        }
+        if !depths.is_balanced() {
+            let blocks: Vec<_> = depths.blocks.iter().filter(|(_, e)| **e != 0).map(|(b, e)| format!("{} {:+}", b, e)).collect();
+            println!("      blocks that move the stack: {}", blocks.join(", "));
+        }
+    }
+}
+
+/// Statically checks every instruction reachable in `filename`, printing each problem with
+/// its address and disassembly and exiting with an error if there are any.
+pub fn verify(filename: &str, source: Source) {
+    let image = Image::load(filename, &source);
+    // Nothing about the challenge applies to an arbitrary image, so start from its entry
+    // points alone
+    let entries = image.resolve_entries(image.entries.clone(), &HashMap::new());
+    let analysis = disasm::recursive_descent(&image.memory, &entries);
+    let problems = verify::verify(&image.memory, &analysis);
+
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    println!("; {} instructions checked, {} problem(s)", analysis.code.len(), problems.len());
+
+    if !problems.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// Lists where each routine in the signature library (`library`, or the built-in one) turns
+/// up in memory, with the calling convention it gives.
+pub fn signatures(filename: &str, library: Option<&str>, source: Source) {
+    let image = Image::load(filename, &source);
+    let library = match library {
+        Some(library) => library::Library::load(library).unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }),
+        None => library::Library::builtin(),
+    };
+
+    let found = library.scan(&image.memory);
+    println!("; {} match(es) for {} routine(s)", found.len(), library.routines.len());
+
+    for (address, routine) in found {
+        let signature = routine.signature.map(|s| format!(" ; {}", s)).unwrap_or_default();
+        println!("{:5} {}{}", address, routine.label, signature);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A random mix of instructions (valid or not) and data, so every path through the
+    /// disassembler gets exercised.
+    fn random_image(rng: &mut util::Rng) -> Vec<u16> {
+        let length = 1 + rng.below(200) as usize;
+        let mut memory = Vec::new();
+
+        while memory.len() < length {
+            if rng.below(4) == 0 {
+                memory.push(rng.next() as u16);
+                continue;
+            }
+
+            let opcode = rng.below(22) as u16;
+            memory.push(opcode);
+
+            for _ in 0..opcode::Opcode::from(opcode).arg_count() {
+                let arg = match rng.below(10) {
+                    0..=4 => 32768 + rng.below(8),
+                    5..=8 => rng.below(length as u64),
+                    _ => rng.next() % 65536,
+                };
+                memory.push(arg as u16);
+            }
+        }
+
+        memory
+    }
+
+    #[test]
+    fn random_images_roundtrip() {
+        let mut rng = util::Rng::new(1);
+        for i in 0..300 {
+            let memory = random_image(&mut rng);
+            let entries = vec![0, rng.below(memory.len() as u64) as usize];
+            assert_eq!(reassemble(&memory, &entries), Ok(()), "random image {}: {:?}", i, memory);
+        }
+    }
 }
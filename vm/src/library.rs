@@ -0,0 +1,215 @@
+//! A library of routine signatures: instruction patterns for routines that recur across
+//! Synacor programs, so they can be labelled wherever they turn up. The format is described
+//! at the top of `signatures.txt`, which is built in.
+
+use super::convention::Signature;
+use super::disasm;
+use super::opcode::{self, Opcode};
+use std::collections::{BTreeMap, HashMap};
+
+const BUILTIN: &str = include_str!("../signatures.txt");
+
+enum Operand {
+    Exact(opcode::Operand),
+    /// `*`
+    Any,
+    /// `r*`
+    Register,
+    /// `#`
+    Literal,
+}
+
+impl Operand {
+    fn parse(text: &str) -> Option<Operand> {
+        let operand = match text {
+            "*" => Operand::Any,
+            "r*" => Operand::Register,
+            "#" => Operand::Literal,
+            _ => match text.strip_prefix('r') {
+                Some(r) => Operand::Exact(opcode::Operand::Register(r.parse().ok().filter(|&r| r < 8)?)),
+                None => Operand::Exact(opcode::Operand::Literal(text.parse().ok().filter(|&w| w <= 32767)?)),
+            },
+        };
+
+        Some(operand)
+    }
+
+    fn matches(&self, operand: opcode::Operand) -> bool {
+        match (self, operand) {
+            (Operand::Exact(exact), operand) => *exact == operand,
+            (Operand::Any, _) => true,
+            (Operand::Register, opcode::Operand::Register(_)) => true,
+            (Operand::Literal, opcode::Operand::Literal(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+struct Pattern {
+    opcode: Opcode,
+    operands: Vec<Operand>,
+}
+
+impl Pattern {
+    fn parse(line: &str) -> Result<Pattern, String> {
+        let (mnemonic, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let opcode = Opcode::from_mnemonic(mnemonic).ok_or(format!("unknown instruction `{}`", mnemonic))?;
+
+        let operands = rest
+            .split(',')
+            .map(str::trim)
+            .filter(|o| !o.is_empty())
+            .map(|o| Operand::parse(o).ok_or(format!("bad operand `{}`", o)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if operands.len() != opcode.arg_count() {
+            return Err(format!("`{}` takes {} operand(s)", mnemonic, opcode.arg_count()));
+        }
+
+        Ok(Pattern { opcode, operands })
+    }
+}
+
+pub struct Routine {
+    pub label: String,
+    pub signature: Option<Signature>,
+    patterns: Vec<Pattern>,
+}
+
+impl Routine {
+    fn matches_at(&self, memory: &[u16], address: usize) -> bool {
+        let mut address = address;
+
+        for pattern in &self.patterns {
+            let instruction = match disasm::decode(memory, address) {
+                Some(instruction) if instruction.opcode == pattern.opcode => instruction,
+                _ => return false,
+            };
+            if !pattern.operands.iter().zip(&instruction.operands).all(|(p, &o)| p.matches(o)) {
+                return false;
+            }
+            address = instruction.next();
+        }
+
+        true
+    }
+}
+
+pub struct Library {
+    pub routines: Vec<Routine>,
+}
+
+impl Library {
+    pub fn builtin() -> Library {
+        Library::parse(BUILTIN).unwrap_or_else(|e| panic!("Invalid built-in signature library: {}", e))
+    }
+
+    /// Reads and parses `filename`, or returns what's wrong with it.
+    pub fn load(filename: &str) -> Result<Library, String> {
+        let text = std::fs::read_to_string(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        Library::parse(&text).map_err(|e| format!("{}: {}", filename, e))
+    }
+
+    pub fn parse(text: &str) -> Result<Library, String> {
+        let mut routines: Vec<Routine> = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap().trim();
+            let error = |message: String| format!("line {}: {}", i + 1, message);
+
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(label) = line.strip_prefix("routine ") {
+                routines.push(Routine {
+                    label: label.trim().to_owned(),
+                    signature: None,
+                    patterns: Vec::new(),
+                });
+                continue;
+            }
+
+            let routine = routines.last_mut().ok_or_else(|| error("expected `routine <label>` first".to_owned()))?;
+            match line.strip_prefix("signature ") {
+                Some(signature) => {
+                    let signature = Signature::parse(signature.trim());
+                    routine.signature = Some(signature.ok_or_else(|| error(format!("bad signature `{}`", line)))?);
+                }
+                None => routine.patterns.push(Pattern::parse(line).map_err(error)?),
+            }
+        }
+
+        match routines.iter().find(|r| r.patterns.is_empty()) {
+            Some(routine) => Err(format!("routine `{}` has no instructions", routine.label)),
+            None => Ok(Library { routines }),
+        }
+    }
+
+    /// Every address in `memory` where one of the routines starts, in order.
+    pub fn scan(&self, memory: &[u16]) -> Vec<(usize, &Routine)> {
+        (0..memory.len())
+            .flat_map(|address| {
+                self.routines
+                    .iter()
+                    .filter(move |r| r.matches_at(memory, address))
+                    .map(move |r| (address, r))
+            })
+            .collect()
+    }
+
+    /// Labels each routine found in `memory`, leaving existing labels alone, and returns the
+    /// signatures the library gives for them, by address.
+    pub fn apply(&self, memory: &[u16], labels: &mut HashMap<usize, String>) -> BTreeMap<usize, Signature> {
+        let mut signatures = BTreeMap::new();
+
+        for (address, routine) in self.scan(memory) {
+            labels.entry(address).or_insert_with(|| routine.label.clone());
+            if let Some(signature) = routine.signature {
+                signatures.insert(address, signature);
+            }
+        }
+
+        signatures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    const PROGRAM: &str = "set r0 5\nset r1 r2\nset r0 r2\nadd r1 r1 1\nhalt";
+
+    /// The addresses in `PROGRAM` where a routine made of `patterns` starts.
+    fn found(patterns: &str) -> Vec<usize> {
+        let library = Library::parse(&format!("routine test\n{}", patterns)).unwrap();
+        let memory = asm::assemble(PROGRAM).ok().unwrap();
+        library.scan(&memory).into_iter().map(|(address, _)| address).collect()
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert_eq!(found("set *, *"), vec![0, 3, 6]);
+        assert_eq!(found("set r*, r*"), vec![3, 6]);
+        assert_eq!(found("set r*, #"), vec![0]);
+        assert_eq!(found("set r0, *"), vec![0, 6]);
+        assert_eq!(found("set *, r2"), vec![3, 6]);
+        assert_eq!(found("set r0, 5"), vec![0]);
+        assert_eq!(found("set #, *"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn matches_every_instruction_in_order() {
+        assert_eq!(found("set r0, r*\nadd r*, r*, #\nhalt"), vec![6]);
+        assert_eq!(found("set r0, r*\nhalt"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn rejects_bad_patterns() {
+        assert!(Library::parse("routine test\nset r8, 1").is_err());
+        assert!(Library::parse("routine test\nset r0, 32768").is_err());
+        assert!(Library::parse("routine test\nset r0").is_err());
+        assert!(Library::parse("routine test").is_err());
+        assert!(Library::builtin().routines.iter().all(|r| !r.patterns.is_empty()));
+    }
+}
@@ -1,6 +1,114 @@
+const DEFAULT_BINARY: &str = "/Users/tim/dev/synacor/challenge.bin";
+const DEFAULT_GDB_PORT: u16 = 1234;
+
+// Flags followed by a value, which mustn't be mistaken for a positional argument.
+const VALUE_FLAGS: [&str; 7] = [
+    "--snapshot",
+    "--profile",
+    "-o",
+    "--from",
+    "--symbols",
+    "--steps",
+    "--library",
+];
+
 fn main() {
-    let filename = "/Users/tim/dev/synacor/challenge.bin";
-    vm::start(filename);
-    // vm::export(filename);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["tui", rest @ ..] => vm::start_tui(binary(rest)),
+        ["gdb", rest @ ..] => {
+            let port = positional(rest).get(1).map(|p| p.parse().expect("Invalid port"));
+            vm::start_gdb(binary(rest), port.unwrap_or(DEFAULT_GDB_PORT))
+        }
+        ["dap"] => vm::start_dap(),
+        ["hexdump", file, start, count, flags @ ..] => vm::hexdump(
+            file,
+            start.parse().expect("Invalid address"),
+            count.parse().expect("Invalid count"),
+            flags.contains(&"--dec"),
+            flags.contains(&"--wide"),
+        ),
+        ["disasm", rest @ ..] => vm::export(
+            binary(rest),
+            rest.contains(&"--recursive"),
+            rest.contains(&"--canonical"),
+            source(rest),
+        ),
+        ["callgraph", rest @ ..] => vm::callgraph(
+            binary(rest),
+            source(rest),
+            value(rest, "--profile"),
+            rest.contains(&"--json"),
+        ),
+        ["cfg", rest @ ..] => {
+            let entry = positional(rest).get(1).map(|a| a.parse().expect("Invalid address"));
+            vm::cfg(binary(rest), entry.expect("Missing function address"), source(rest))
+        }
+        ["strings", rest @ ..] => vm::strings(binary(rest), source(rest)),
+        ["asm", rest @ ..] => vm::assemble(
+            positional(rest).first().expect("Missing assembly file"),
+            value(rest, "-o").unwrap_or("out.bin"),
+        ),
+        ["roundtrip", rest @ ..] => vm::roundtrip(binary(rest)),
+        ["decompile", rest @ ..] => {
+            let entry = positional(rest).get(1).map(|a| a.parse().expect("Invalid address"));
+            vm::decompile(binary(rest), entry, source(rest))
+        }
+        ["reach", rest @ ..] => {
+            let target = positional(rest).get(1).map(|a| a.parse().expect("Invalid address"));
+            let symbols: Vec<_> = value(rest, "--symbols").unwrap_or("r7").split(',').collect();
+            vm::reach(
+                binary(rest),
+                target.expect("Missing target address"),
+                value(rest, "--from").map(|a| a.parse().expect("Invalid address")),
+                &symbols,
+                value(rest, "--steps").map(|s| s.parse().expect("Invalid step count")).unwrap_or(1_000_000),
+                source(rest),
+            )
+        }
+        ["signatures", rest @ ..] => vm::signatures(binary(rest), value(rest, "--library"), source(rest)),
+        ["verify", rest @ ..] => vm::verify(binary(rest), source(rest)),
+        ["stack", rest @ ..] => vm::stack(binary(rest), source(rest)),
+        ["taint", rest @ ..] => {
+            let input = positional(rest).get(1).copied();
+            vm::taint(binary(rest), input.expect("Missing input file"))
+        }
+        rest => vm::start(binary(rest)),
+    }
+}
+
+fn positional<'a>(args: &[&'a str]) -> Vec<&'a str> {
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if VALUE_FLAGS.contains(arg) {
+            args.next();
+        } else if !arg.starts_with("--") {
+            positional.push(*arg);
+        }
+    }
+
+    positional
+}
+
+fn value<'a>(args: &[&'a str], flag: &str) -> Option<&'a str> {
+    let index = args.iter().position(|a| *a == flag)?;
+    args.get(index + 1).copied()
+}
+
+fn binary<'a>(args: &[&'a str]) -> &'a str {
+    positional(args).first().copied().unwrap_or(DEFAULT_BINARY)
+}
 
+fn source<'a>(args: &[&'a str]) -> vm::Source<'a> {
+    if let Some(snapshot) = value(args, "--snapshot") {
+        vm::Source::Snapshot(snapshot)
+    } else if args.contains(&"--after-boot") {
+        vm::Source::AfterBoot
+    } else {
+        vm::Source::Binary
+    }
 }
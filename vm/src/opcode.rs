@@ -1,4 +1,10 @@
-#[derive(Debug)]
+// Instruction names from the spec, indexed by opcode.
+const MNEMONICS: [&str; 22] = [
+    "halt", "set", "push", "pop", "eq", "gt", "jmp", "jt", "jf", "add", "mult", "mod", "and", "or",
+    "not", "rmem", "wmem", "call", "ret", "out", "in", "noop",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Opcode {
     Halt,
     Set,
@@ -31,31 +37,93 @@ impl std::fmt::Display for Opcode {
 }
 
 impl Opcode {
+    /// The word this opcode is encoded as, or `None` for `Unknown`.
+    pub fn code(&self) -> Option<u16> {
+        (0..MNEMONICS.len() as u16).find(|&c| Opcode::from(c) == *self)
+    }
+
+    /// The name the spec gives the instruction, e.g. `jt`.
+    pub fn mnemonic(&self) -> Option<&'static str> {
+        self.code().map(|c| MNEMONICS[c as usize])
+    }
+
+    pub fn from_mnemonic(name: &str) -> Option<Opcode> {
+        let code = MNEMONICS.iter().position(|m| m.eq_ignore_ascii_case(name))?;
+        Some(Opcode::from(code as u16))
+    }
+
     pub fn arg_count(&self) -> usize {
+        self.roles().len()
+    }
+
+    /// What each operand is for, in order.
+    pub fn roles(&self) -> &'static [Role] {
+        use Role::*;
+
+        match self {
+            Opcode::Halt | Opcode::Ret | Opcode::Noop | Opcode::Unknown => &[],
+            Opcode::Set | Opcode::Not => &[Destination, Source],
+            Opcode::Push | Opcode::Out => &[Source],
+            Opcode::Pop | Opcode::In => &[Destination],
+            Opcode::Eq | Opcode::Gt | Opcode::Add | Opcode::Mult | Opcode::Mod | Opcode::And | Opcode::Or => {
+                &[Destination, Source, Source]
+            }
+            Opcode::Jmp | Opcode::Call => &[Target],
+            Opcode::JmpIfTrue | Opcode::JmpIfFalse => &[Source, Target],
+            Opcode::RMem => &[Destination, Address],
+            Opcode::WMem => &[Address, Source],
+        }
+    }
+}
+
+/// What an operand is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// The register the result goes in.
+    Destination,
+    /// A value that's read.
+    Source,
+    /// The memory address read or written.
+    Address,
+    /// Where a jump or call goes.
+    Target,
+}
+
+/// An operand word: a literal, one of the eight registers, or a word past the last register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Literal(u16),
+    Register(usize),
+    Invalid(u16),
+}
+
+impl Operand {
+    pub fn from_word(word: u16) -> Operand {
+        match word {
+            0..=32767 => Operand::Literal(word),
+            32768..=32775 => Operand::Register((word - 32768) as usize),
+            _ => Operand::Invalid(word),
+        }
+    }
+
+    pub fn word(self) -> u16 {
+        match self {
+            Operand::Literal(word) | Operand::Invalid(word) => word,
+            Operand::Register(r) => 32768 + r as u16,
+        }
+    }
+
+    pub fn register(self) -> Option<usize> {
+        match self {
+            Operand::Register(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    pub fn literal(self) -> Option<u16> {
         match self {
-            Opcode::Halt => 0,
-            Opcode::Set => 2,
-            Opcode::Push => 1,
-            Opcode::Pop => 1,
-            Opcode::Eq => 3,
-            Opcode::Gt => 3,
-            Opcode::Jmp => 1,
-            Opcode::JmpIfTrue => 2,
-            Opcode::JmpIfFalse => 2,
-            Opcode::Add => 3,
-            Opcode::Mult => 3,
-            Opcode::Mod => 3,
-            Opcode::And => 3,
-            Opcode::Or => 3,
-            Opcode::Not => 2,
-            Opcode::RMem => 2,
-            Opcode::WMem => 2,
-            Opcode::Call => 1,
-            Opcode::Ret => 0,
-            Opcode::Out => 1,
-            Opcode::In => 1,
-            Opcode::Noop => 0,
-            Opcode::Unknown => 0,
+            Operand::Literal(c) => Some(c),
+            _ => None,
         }
     }
 }
@@ -0,0 +1,82 @@
+//! Searching program memory for values, word sequences and strings.
+
+use super::util;
+
+pub enum Pattern {
+    /// A single 16-bit word.
+    Value(u16),
+    /// A run of consecutive words.
+    Words(Vec<u16>),
+    /// Plain ASCII text, one character per word.
+    Ascii(String),
+    /// A Synacor string: a length word followed by the characters.
+    Prefixed(String),
+    /// Text obfuscated with `key`, as decoded by `util::decode`. Only printable ASCII matches.
+    Coded { text: String, key: u16 },
+}
+
+impl Pattern {
+    /// Parses the arguments to the debugger's `find` command:
+    /// `v <value>`, `w <word>...`, `s <text>`, `p <text>` or `k <key> <text>`.
+    pub fn parse(args: &[&str]) -> Option<Pattern> {
+        let (kind, rest) = args.split_first()?;
+
+        let pattern = match *kind {
+            "v" => Pattern::Value(rest.first()?.parse().ok()?),
+            "w" => Pattern::Words(rest.iter().map(|w| w.parse().ok()).collect::<Option<_>>()?),
+            "s" => Pattern::Ascii(rest.join(" ")),
+            "p" => Pattern::Prefixed(rest.join(" ")),
+            "k" => {
+                let (key, text) = rest.split_first()?;
+                Pattern::Coded {
+                    text: text.join(" "),
+                    key: key.parse().ok()?,
+                }
+            }
+            _ => return None,
+        };
+
+        Some(pattern)
+    }
+
+    fn matches_at(&self, memory: &[u16], address: usize) -> bool {
+        let words_match = |words: &[u16], at: usize| {
+            !words.is_empty() && memory.get(at..at + words.len()) == Some(words)
+        };
+
+        match self {
+            Pattern::Value(value) => memory[address] == *value,
+            Pattern::Words(words) => words_match(words, address),
+            Pattern::Ascii(text) => {
+                let words: Vec<u16> = text.chars().map(|c| c as u16).collect();
+                words_match(&words, address)
+            }
+            Pattern::Prefixed(text) => {
+                let words: Vec<u16> = text.chars().map(|c| c as u16).collect();
+                memory[address] as usize == words.len() && words_match(&words, address + 1)
+            }
+            Pattern::Coded { text, key } => {
+                let length = text.chars().count();
+
+                match memory.get(address..address + length) {
+                    Some(words) if length > 0 => words
+                        .iter()
+                        .zip(text.chars())
+                        .all(|(w, c)| {
+                            // Compare the decoded word itself, so `.` only matches a real `.`
+                            let decoded = util::decode(*w, *key);
+                            (32..127).contains(&decoded) && decoded == c as u16
+                        }),
+                    _ => false,
+                }
+            }
+        }
+    }
+}
+
+/// Every address in `memory` where `pattern` starts.
+pub fn find(memory: &[u16], pattern: &Pattern) -> Vec<usize> {
+    (0..memory.len())
+        .filter(|&address| pattern.matches_at(memory, address))
+        .collect()
+}
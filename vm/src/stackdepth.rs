@@ -0,0 +1,173 @@
+//! Static stack-depth analysis: how many words each block and function leaves on the stack,
+//! measured from the return address a `Call` pushed on the way in.
+//!
+//! A function is balanced when every path returns with the return address back on top.
+//! Anything else breaks the debugger's `call_stack`, which assumes every `Ret` goes back to
+//! the instruction after the matching `Call`.
+
+use super::callgraph::{CallGraph, Function};
+use super::cfg::{Cfg, Target};
+use super::disasm::Analysis;
+use super::ir::Effect;
+use std::collections::BTreeMap;
+
+// Effects are settled by iterating over the whole program; mutual recursion that keeps
+// growing the stack would never settle, so give up after this many rounds.
+const MAX_ROUNDS: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// Paths reach the block at `block` with the stack at different depths.
+    Mismatch { block: usize, depths: (i32, i32) },
+    /// A `Pop` at `depth` 0 or below, which takes the return address or the caller's data.
+    PopsReturn { address: usize, depth: i32 },
+    /// A `Ret`, or a jump into another function, with something other than the return
+    /// address on top.
+    Unbalanced { address: usize, depth: i32 },
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Problem::Mismatch { block, depths } => {
+                write!(f, "{}: reached at depth {} and at depth {}", block, depths.0, depths.1)
+            }
+            Problem::PopsReturn { address, depth: 0 } => write!(f, "{}: pops the return address as data", address),
+            Problem::PopsReturn { address, depth } => {
+                write!(f, "{}: pops {} word(s) below the return address", address, 1 - depth)
+            }
+            Problem::Unbalanced { address, depth } => write!(f, "{}: leaves with the stack at depth {}", address, depth),
+        }
+    }
+}
+
+/// The stack depths through one function.
+pub struct Depths {
+    /// The depth before each instruction, in words pushed since the return address.
+    pub at: BTreeMap<usize, i32>,
+    /// The net effect of each block, by start.
+    pub blocks: BTreeMap<usize, i32>,
+    /// The depth at every exit, if they all agree: the words the function leaves on its
+    /// caller's stack, or takes off it if negative. `None` if it never returns.
+    pub effect: Option<i32>,
+    /// The deepest the function's own pushes go.
+    pub max: i32,
+    pub problems: Vec<Problem>,
+}
+
+impl Depths {
+    pub fn is_balanced(&self) -> bool {
+        self.problems.is_empty() && self.effect.unwrap_or(0) == 0
+    }
+}
+
+/// The net effect of calling whatever `address` calls, taking the first known callee.
+fn call_effect(analysis: &Analysis, graph: &CallGraph, effects: &BTreeMap<usize, i32>, address: usize) -> i32 {
+    let direct = analysis.code[&address].target();
+    let indirect = graph.targets.get(&address).into_iter().flatten().copied();
+
+    direct.into_iter().chain(indirect).find_map(|c| effects.get(&c).copied()).unwrap_or(0)
+}
+
+fn depths(analysis: &Analysis, graph: &CallGraph, effects: &BTreeMap<usize, i32>, function: &Function) -> Depths {
+    let cfg = Cfg::build(analysis, function);
+    let mut depths = Depths {
+        at: BTreeMap::new(),
+        blocks: BTreeMap::new(),
+        effect: None,
+        max: 0,
+        problems: Vec::new(),
+    };
+    let mut incoming: BTreeMap<usize, i32> = BTreeMap::new();
+    let mut exits: Vec<i32> = Vec::new();
+    let mut pending = vec![(cfg.entry, 0)];
+
+    while let Some((start, depth)) = pending.pop() {
+        // The first depth to reach a block wins; any other is reported once
+        match incoming.get(&start) {
+            Some(&first) if first == depth => continue,
+            Some(&first) => {
+                let problem = Problem::Mismatch {
+                    block: start,
+                    depths: (first, depth),
+                };
+                if !depths.problems.iter().any(|p| matches!(p, Problem::Mismatch { block, .. } if *block == start)) {
+                    depths.problems.push(problem);
+                }
+                continue;
+            }
+            None => incoming.insert(start, depth),
+        };
+
+        let block = &cfg.blocks[&start];
+        let mut depth = depth;
+
+        for &address in &block.instructions {
+            depths.at.insert(address, depth);
+
+            match analysis.code[&address].lift() {
+                Some(Effect::Push(_)) => depth += 1,
+                Some(Effect::Pop(_)) => {
+                    if depth <= 0 {
+                        depths.problems.push(Problem::PopsReturn { address, depth });
+                    }
+                    depth -= 1;
+                }
+                Some(Effect::Call(_)) => depth += call_effect(analysis, graph, effects, address),
+                Some(Effect::Ret) => {
+                    if depth != 0 {
+                        depths.problems.push(Problem::Unbalanced { address, depth });
+                    }
+                    exits.push(depth);
+                }
+                _ => {}
+            }
+            depths.max = depths.max.max(depth);
+        }
+        depths.blocks.insert(start, depth - incoming[&start]);
+
+        let last = *block.instructions.last().unwrap();
+        for (target, _) in &block.successors {
+            match target {
+                Target::Block(b) => pending.push((*b, depth)),
+                // A tail call returns to our caller with whatever the callee leaves
+                Target::Function(t) => {
+                    if depth != 0 {
+                        depths.problems.push(Problem::Unbalanced { address: last, depth });
+                    }
+                    exits.push(depth + effects.get(t).copied().unwrap_or(0));
+                }
+                Target::Exit | Target::Indirect => {}
+            }
+        }
+    }
+
+    depths.problems.sort_by_key(|p| match p {
+        Problem::Mismatch { block, .. } => *block,
+        Problem::PopsReturn { address, .. } | Problem::Unbalanced { address, .. } => *address,
+    });
+    depths.problems.dedup();
+    depths.effect = exits.first().copied().filter(|e| exits.iter().all(|x| x == e));
+    depths
+}
+
+/// The stack depths through every function in `graph`, by entry address.
+pub fn analyse(analysis: &Analysis, graph: &CallGraph) -> BTreeMap<usize, Depths> {
+    let mut effects: BTreeMap<usize, i32> = BTreeMap::new();
+
+    for _ in 0..MAX_ROUNDS {
+        let all: BTreeMap<usize, Depths> = graph
+            .functions
+            .values()
+            .map(|f| (f.entry, depths(analysis, graph, &effects, f)))
+            .collect();
+        let settled = all.iter().map(|(&e, d)| (e, d.effect.unwrap_or(0))).collect();
+
+        if settled == effects {
+            return all;
+        }
+        effects = settled;
+    }
+
+    graph.functions.values().map(|f| (f.entry, depths(analysis, graph, &effects, f))).collect()
+}
@@ -0,0 +1,262 @@
+//! Finding the length-prefixed strings in memory, including ones obfuscated with a key.
+
+use super::callgraph::CallGraph;
+use super::cfg::Cfg;
+use super::constprop::{self, Value};
+use super::disasm::Analysis;
+use super::opcode::Opcode;
+use super::symbols;
+use super::util;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+// Anything shorter is too likely to turn up by chance.
+const MIN_LENGTH: usize = 2;
+
+pub struct Found {
+    pub address: usize,
+    /// The key the characters are decoded with, or `None` for plain text.
+    pub key: Option<u16>,
+    pub text: String,
+}
+
+impl Found {
+    pub fn len(&self) -> usize {
+        self.text.chars().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// A short form for the end of a listing line, e.g. `"You see" (key 4457)`.
+    pub fn describe(&self) -> String {
+        match self.key {
+            Some(key) => format!("{:?} (key {})", self.text, key),
+            None => format!("{:?}", self.text),
+        }
+    }
+}
+
+/// Strings found in memory, by the address of their length word.
+pub struct Table {
+    strings: BTreeMap<usize, Found>,
+}
+
+impl Table {
+    pub fn get(&self, address: usize) -> Option<&Found> {
+        self.strings.get(&address)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Found> {
+        self.strings.values()
+    }
+
+    /// The string whose length word or characters include `address`.
+    pub fn containing(&self, address: usize) -> Option<&Found> {
+        let (_, found) = self.strings.range(..=address).next_back()?;
+        Some(found).filter(|f| address <= f.address + f.len())
+    }
+}
+
+/// A call, along with the registers just before it.
+struct Site {
+    address: usize,
+    function: usize,
+    /// Whether the call is in its function's first block, so `Value::Entry` is an argument.
+    in_entry_block: bool,
+    target: Option<usize>,
+    registers: constprop::Registers,
+}
+
+/// Every call in a function starting at `entries` or found by `CallGraph::build`.
+fn call_sites(analysis: &Analysis, entries: &[usize], labels: &HashMap<usize, String>) -> Vec<Site> {
+    let graph = CallGraph::build(analysis, entries, labels, None);
+    let mut sites = Vec::new();
+
+    for function in graph.functions.values() {
+        for block in Cfg::build(analysis, function).blocks.values() {
+            let registers = constprop::through_block(analysis, &block.instructions);
+
+            for (address, registers) in block.instructions.iter().zip(registers) {
+                let instruction = &analysis.code[address];
+
+                if instruction.opcode == Opcode::Call {
+                    sites.push(Site {
+                        address: *address,
+                        function: function.entry,
+                        in_entry_block: block.start == function.entry,
+                        target: instruction.target(),
+                        registers,
+                    });
+                }
+            }
+        }
+    }
+
+    sites
+}
+
+/// Routines whose addresses are passed to one of the `symbols::MAPPERS`. They're only
+/// ever called through a register, so recursive descent can't find them by itself.
+pub fn routines(analysis: &Analysis, labels: &HashMap<usize, String>) -> BTreeSet<usize> {
+    let mut routines = BTreeSet::new();
+
+    for site in call_sites(analysis, &[0], labels) {
+        for (mapper, _, routine) in symbols::MAPPERS {
+            if let (true, Value::Const(r)) = (site.target == Some(mapper), site.registers[routine]) {
+                routines.insert(r as usize);
+            }
+        }
+    }
+
+    routines
+}
+
+/// The keys passed to the decoder, worked out by propagating constants to each call.
+///
+/// A routine that hands one of its own registers to the decoder as the key (the character
+/// printer passed to "Map fn" does this with r2) counts as a decoder too, so its callers
+/// and anything passing its address along contribute keys as well.
+pub fn keys(analysis: &Analysis, labels: &HashMap<usize, String>) -> BTreeSet<u16> {
+    let entries: Vec<_> = std::iter::once(0).chain(routines(analysis, labels)).collect();
+    let sites = call_sites(analysis, &entries, labels);
+    let mut keys = BTreeSet::new();
+    // Routines that call the decoder with one of their registers as the key
+    let mut keyed: HashMap<usize, usize> = HashMap::new();
+
+    for site in sites.iter().filter(|s| s.target == Some(symbols::DECODER)) {
+        match site.registers[1] {
+            Value::Const(key) => {
+                keys.insert(key);
+            }
+            Value::Entry(r) if site.in_entry_block => {
+                keyed.insert(site.function, r);
+            }
+            _ => {}
+        }
+    }
+
+    for site in &sites {
+        // Either calling the routine directly or passing its address to something that will
+        let routines = site
+            .registers
+            .iter()
+            .filter_map(|v| match v {
+                Value::Const(c) => Some(*c as usize),
+                _ => None,
+            })
+            .chain(site.target);
+
+        for routine in routines {
+            if let Some(Value::Const(key)) = keyed.get(&routine).map(|&r| site.registers[r]) {
+                keys.insert(key);
+            }
+        }
+    }
+
+    keys
+}
+
+fn is_printable(c: char) -> bool {
+    (' '..='~').contains(&c) || c == '\n'
+}
+
+fn decode(words: &[u16], key: Option<u16>) -> Option<String> {
+    words
+        .iter()
+        .map(|&w| match key {
+            Some(key) => util::to_ascii(util::decode(w, key)),
+            None => util::to_ascii(w),
+        })
+        .map(|c| c.filter(|c| is_printable(*c)))
+        .collect()
+}
+
+/// Scans `memory` for a length word followed by that many characters that are all
+/// printable, either as they are or decoded with one of `keys`.
+pub fn scan(memory: &[u16], keys: &BTreeSet<u16>) -> Table {
+    let mut strings = BTreeMap::new();
+    let mut address = 0;
+
+    while address < memory.len() {
+        let length = memory[address] as usize;
+        let words = match memory.get(address + 1..address + 1 + length) {
+            Some(words) if length >= MIN_LENGTH => words,
+            _ => {
+                address += 1;
+                continue;
+            }
+        };
+
+        let found = std::iter::once(None)
+            .chain(keys.iter().copied().map(Some))
+            .find_map(|key| decode(words, key).map(|text| Found { address, key, text }));
+
+        match found {
+            Some(found) => {
+                strings.insert(address, found);
+                address += length + 1;
+            }
+            None => address += 1,
+        }
+    }
+
+    Table { strings }
+}
+
+/// Reads the length-prefixed string at `address`, decoding it with `key` if there is one.
+fn read(memory: &[u16], address: usize, key: Option<u16>) -> Option<String> {
+    let length = *memory.get(address)? as usize;
+    decode(memory.get(address + 1..address + 1 + length)?, key)
+}
+
+/// Comments for calls that print or map over a string whose address is known at the call,
+/// e.g. `prints "What do you do?"`, by call site.
+pub fn calls(memory: &[u16], analysis: &Analysis, labels: &HashMap<usize, String>) -> BTreeMap<usize, String> {
+    let entries: Vec<_> = std::iter::once(0).chain(routines(analysis, labels)).collect();
+    let mut comments = BTreeMap::new();
+
+    let constant = |site: &Site, r: usize| match site.registers[r] {
+        Value::Const(c) => Some(c),
+        _ => None,
+    };
+
+    for site in call_sites(analysis, &entries, labels) {
+        let target = match site.target {
+            Some(target) => target,
+            None => continue,
+        };
+
+        let print_fn = symbols::PRINT_FNS.iter().find(|(f, _)| *f == target);
+        let mapper = symbols::MAPPERS.iter().find(|(f, _, _)| *f == target);
+
+        let comment = if let Some((_, string)) = print_fn {
+            let text = constant(&site, *string).and_then(|a| read(memory, a as usize, None));
+            text.map(|text| format!("prints {:?}", text))
+        } else if let Some((_, string, routine)) = mapper {
+            let address = constant(&site, *string);
+            let routine = constant(&site, *routine).map(|r| r as usize);
+            let printer = symbols::PRINTERS.iter().find(|(p, _)| Some(*p) == routine);
+
+            match (address, routine, printer) {
+                (Some(address), _, Some((_, None))) => {
+                    read(memory, address as usize, None).map(|text| format!("prints {:?}", text))
+                }
+                (Some(address), _, Some((_, Some(key)))) => constant(&site, *key)
+                    .and_then(|key| read(memory, address as usize, Some(key)))
+                    .map(|text| format!("prints {:?}", text)),
+                (Some(address), Some(routine), None) => read(memory, address as usize, None)
+                    .map(|text| format!("{} over {:?}", symbols::function_name(routine, labels), text)),
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        if let Some(comment) = comment {
+            comments.insert(site.address, comment);
+        }
+    }
+
+    comments
+}
@@ -0,0 +1,648 @@
+//! Symbolic execution: running code with some registers, memory cells or input characters
+//! left unknown, and collecting the conditions each path depends on. A small bounded solver
+//! then finds values that satisfy them.
+
+use super::ir::{self, Effect};
+use super::opcode::{Opcode, Operand};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::rc::Rc;
+
+// How much searching a branch gets before it's assumed feasible.
+const FEASIBILITY_BUDGET: usize = 1_000_000;
+// How many characters a path may read before it's abandoned.
+const MAX_INPUT: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Symbol {
+    Register(usize),
+    Memory(usize),
+    /// The nth character read by `In`.
+    Input(usize),
+}
+
+impl Symbol {
+    /// Parses a register or memory cell, like `r7` or `mem[2732]`.
+    pub fn parse(text: &str) -> Option<Symbol> {
+        match text.strip_prefix('r').and_then(|r| r.parse().ok()) {
+            Some(r @ 0..=7) => Some(Symbol::Register(r)),
+            Some(_) => None,
+            None => {
+                let address = text.strip_prefix("mem[")?.strip_suffix(']')?.parse().ok()?;
+                Some(Symbol::Memory(address))
+            }
+        }
+    }
+
+    /// The values the symbol could take: any number, or a printable character for input.
+    fn domain(&self) -> Vec<u16> {
+        match self {
+            Symbol::Input(_) => std::iter::once(10).chain(32..127).collect(),
+            _ => (0..32768).collect(),
+        }
+    }
+
+    pub fn describe(&self, value: u16) -> String {
+        match (self, value) {
+            (Symbol::Input(_), 10) => "'\\n'".to_owned(),
+            (Symbol::Input(_), c) => format!("{:?}", c as u8 as char),
+            (_, value) => value.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Symbol::Register(r) => write!(f, "r{}", r),
+            Symbol::Memory(address) => write!(f, "mem[{}]", address),
+            Symbol::Input(n) => write!(f, "in[{}]", n),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Expr {
+    Const(u16),
+    Symbol(Symbol),
+    /// One of the three-operand arithmetic or comparison opcodes applied to two values.
+    Binary(Opcode, Rc<Expr>, Rc<Expr>),
+    Not(Rc<Expr>),
+}
+
+impl Expr {
+    fn binary(opcode: Opcode, a: Rc<Expr>, b: Rc<Expr>) -> Rc<Expr> {
+        let commutes = matches!(opcode, Opcode::Add | Opcode::Mult | Opcode::Eq | Opcode::And | Opcode::Or);
+
+        match (&*a, &*b) {
+            (Expr::Const(x), Expr::Const(y)) if ir::apply(opcode, *x, *y).is_some() => {
+                Rc::new(Expr::Const(ir::apply(opcode, *x, *y).unwrap()))
+            }
+            // Constants go on the right, so the cases below catch them
+            (Expr::Const(_), _) if commutes => Expr::binary(opcode, b, a),
+            (_, Expr::Const(0)) if opcode == Opcode::Add => a,
+            // Counting up or down in a loop would otherwise build ever deeper sums
+            (Expr::Binary(Opcode::Add, x, c), Expr::Const(y)) if opcode == Opcode::Add => match **c {
+                Expr::Const(c) => Expr::binary(Opcode::Add, x.clone(), Rc::new(Expr::Const(ir::apply(opcode, c, *y).unwrap()))),
+                _ => Rc::new(Expr::Binary(opcode, a, b)),
+            },
+            _ => Rc::new(Expr::Binary(opcode, a, b)),
+        }
+    }
+
+    fn not(a: Rc<Expr>) -> Rc<Expr> {
+        match &*a {
+            Expr::Const(x) => Rc::new(Expr::Const(ir::not(*x))),
+            _ => Rc::new(Expr::Not(a)),
+        }
+    }
+
+    fn constant(&self) -> Option<u16> {
+        match self {
+            Expr::Const(c) => Some(*c),
+            _ => None,
+        }
+    }
+
+    fn symbols(&self, into: &mut BTreeSet<Symbol>) {
+        match self {
+            Expr::Const(_) => {}
+            Expr::Symbol(s) => {
+                into.insert(*s);
+            }
+            Expr::Binary(_, a, b) => {
+                a.symbols(into);
+                b.symbols(into);
+            }
+            Expr::Not(a) => a.symbols(into),
+        }
+    }
+
+    fn evaluate(&self, values: &HashMap<Symbol, u16>) -> Option<u16> {
+        match self {
+            Expr::Const(c) => Some(*c),
+            Expr::Symbol(s) => values.get(s).copied(),
+            Expr::Binary(opcode, a, b) => ir::apply(*opcode, a.evaluate(values)?, b.evaluate(values)?),
+            Expr::Not(a) => a.evaluate(values).map(ir::not),
+        }
+    }
+}
+
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Expr::Const(c) => write!(f, "{}", c),
+            Expr::Symbol(s) => write!(f, "{}", s),
+            Expr::Binary(opcode, a, b) => {
+                let symbol = match opcode {
+                    Opcode::Add => "+",
+                    Opcode::Mult => "*",
+                    Opcode::Mod => "%",
+                    Opcode::Eq => "==",
+                    Opcode::Gt => ">",
+                    Opcode::And => "&",
+                    _ => "|",
+                };
+                write!(f, "({} {} {})", a, symbol, b)
+            }
+            Expr::Not(a) => write!(f, "~{}", a),
+        }
+    }
+}
+
+/// A condition a path depends on: that `expr` is non-zero, or zero if not `holds`.
+#[derive(Clone)]
+pub struct Constraint {
+    pub expr: Rc<Expr>,
+    pub holds: bool,
+}
+
+impl Constraint {
+    /// Adds `constraint` to `constraints` unless it's already there.
+    fn add(constraints: &mut Vec<Constraint>, constraint: Constraint) {
+        let present = constraints.iter().any(|c| c.holds == constraint.holds && c.expr == constraint.expr);
+        if !present {
+            constraints.push(constraint);
+        }
+    }
+
+    fn is_satisfied(&self, values: &HashMap<Symbol, u16>) -> bool {
+        self.expr.evaluate(values).map(|v| (v != 0) == self.holds).unwrap_or(false)
+    }
+}
+
+impl std::fmt::Display for Constraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (&*self.expr, self.holds) {
+            (Expr::Binary(Opcode::Eq, a, b), true) => write!(f, "{} == {}", a, b),
+            (Expr::Binary(Opcode::Eq, a, b), false) => write!(f, "{} != {}", a, b),
+            (Expr::Binary(Opcode::Gt, a, b), true) => write!(f, "{} > {}", a, b),
+            (Expr::Binary(Opcode::Gt, a, b), false) => write!(f, "{} <= {}", a, b),
+            (expr, true) => write!(f, "{} != 0", expr),
+            (expr, false) => write!(f, "{} == 0", expr),
+        }
+    }
+}
+
+/// The values each symbol may still take, narrowed by the constraints that mention it
+/// alone, for symbols that have any.
+pub type Domains = BTreeMap<Symbol, Rc<Vec<u16>>>;
+
+fn domain(domains: &Domains, symbol: Symbol) -> Rc<Vec<u16>> {
+    domains.get(&symbol).cloned().unwrap_or_else(|| Rc::new(symbol.domain()))
+}
+
+/// What the solver found.
+pub struct Solutions {
+    /// The symbols the constraints mention, in the order of each solution's values.
+    pub symbols: Vec<Symbol>,
+    pub values: Vec<Vec<u16>>,
+    /// Whether every combination was tried, so `values` holds every solution up to the limit.
+    pub complete: bool,
+}
+
+/// Searches `domains` for values of the symbols in `constraints` that satisfy them all,
+/// checking at most `budget` constraints and stopping after `limit` solutions. Each
+/// constraint is checked as soon as every symbol it mentions has a value, so whole subtrees
+/// get pruned.
+pub fn solve(constraints: &[Constraint], domains: &Domains, budget: usize, limit: usize) -> Solutions {
+    let mut symbols = BTreeSet::new();
+    for constraint in constraints {
+        constraint.expr.symbols(&mut symbols);
+    }
+    let symbols: Vec<Symbol> = symbols.into_iter().collect();
+
+    // The constraints to check once the symbol at each depth is assigned
+    let mut checks: Vec<Vec<&Constraint>> = vec![Vec::new(); symbols.len()];
+    let mut always = Vec::new();
+    for constraint in constraints {
+        let mut mentioned = BTreeSet::new();
+        constraint.expr.symbols(&mut mentioned);
+        match mentioned.iter().map(|s| symbols.binary_search(s).unwrap()).max() {
+            Some(depth) => checks[depth].push(constraint),
+            None => always.push(constraint),
+        }
+    }
+
+    let mut solutions = Solutions {
+        symbols: symbols.clone(),
+        values: Vec::new(),
+        complete: true,
+    };
+    if !always.iter().all(|c| c.is_satisfied(&HashMap::new())) {
+        return solutions;
+    }
+
+    let domains: Vec<_> = symbols.iter().map(|&s| domain(domains, s)).collect();
+    // The next value to try at each depth
+    let mut next = vec![0; symbols.len()];
+    let mut values = HashMap::new();
+    let mut depth = 0;
+    let mut checked = 0;
+
+    if symbols.is_empty() {
+        solutions.values.push(Vec::new());
+        return solutions;
+    }
+
+    loop {
+        if next[depth] == domains[depth].len() {
+            // Exhausted this level, so backtrack
+            next[depth] = 0;
+            values.remove(&symbols[depth]);
+            if depth == 0 {
+                return solutions;
+            }
+            depth -= 1;
+            continue;
+        }
+
+        if checked >= budget {
+            solutions.complete = false;
+            return solutions;
+        }
+        values.insert(symbols[depth], domains[depth][next[depth]]);
+        next[depth] += 1;
+
+        // The latest constraints are the likeliest to fail
+        let satisfied = checks[depth].iter().rev().all(|c| {
+            checked += 1;
+            c.is_satisfied(&values)
+        });
+        if !satisfied {
+            continue;
+        }
+
+        if depth + 1 < symbols.len() {
+            depth += 1;
+        } else {
+            solutions.values.push(symbols.iter().map(|s| values[s]).collect());
+            if solutions.values.len() == limit {
+                solutions.complete = false;
+                return solutions;
+            }
+        }
+    }
+}
+
+/// Whether the path's constraints can be satisfied, as far as a bounded search can tell.
+/// The last solution found is kept, since it usually satisfies the next constraint too.
+fn is_feasible(path: &mut Path) -> bool {
+    if path.domains.values().any(|d| d.is_empty()) {
+        return false;
+    }
+
+    // Give the model a value for every symbol, and keep each value within its domain
+    for (symbol, domain) in &path.domains {
+        match path.model.get(symbol) {
+            Some(v) if domain.binary_search(v).is_ok() => {}
+            _ => {
+                path.model.insert(*symbol, domain[0]);
+            }
+        }
+    }
+    if path.constraints.iter().all(|c| c.is_satisfied(&path.model)) {
+        return true;
+    }
+
+    let solutions = solve(&path.constraints, &path.domains, FEASIBILITY_BUDGET, 1);
+    if let Some(values) = solutions.values.first() {
+        path.model = solutions.symbols.iter().copied().zip(values.iter().copied()).collect();
+    }
+    !solutions.complete || !solutions.values.is_empty()
+}
+
+/// Where a concrete run stands, for the executor to start from.
+pub struct Start<'a> {
+    pub memory: &'a [u16],
+    pub ip: usize,
+    pub registers: [u16; 8],
+    pub stack: &'a [u16],
+}
+
+#[derive(Clone)]
+struct Path {
+    ip: usize,
+    registers: [Rc<Expr>; 8],
+    stack: Vec<Rc<Expr>>,
+    /// Memory written so far, and the symbolic cells, over the starting memory.
+    memory: HashMap<usize, Rc<Expr>>,
+    constraints: Vec<Constraint>,
+    /// How many characters `In` has read.
+    input: usize,
+    /// What `Out` has written, with `?` for characters that depend on a symbol.
+    output: String,
+    domains: Domains,
+    /// The last values found to satisfy `constraints`.
+    model: HashMap<Symbol, u16>,
+}
+
+impl Path {
+    /// Adds `constraint`, narrowing the domain of the symbol it mentions if there's only one.
+    fn constrain(&mut self, constraint: Constraint) {
+        let mut mentioned = BTreeSet::new();
+        constraint.expr.symbols(&mut mentioned);
+
+        if let [symbol] = mentioned.into_iter().collect::<Vec<_>>()[..] {
+            let mut values = HashMap::new();
+            let narrowed = domain(&self.domains, symbol)
+                .iter()
+                .copied()
+                .filter(|&v| {
+                    values.insert(symbol, v);
+                    constraint.is_satisfied(&values)
+                })
+                .collect();
+            self.domains.insert(symbol, Rc::new(narrowed));
+        }
+
+        Constraint::add(&mut self.constraints, constraint);
+    }
+}
+
+/// A path that got to the target.
+pub struct Reached {
+    pub constraints: Vec<Constraint>,
+    pub domains: Domains,
+    pub output: String,
+}
+
+/// Everything found by `explore`.
+pub struct Report {
+    pub reached: Vec<Reached>,
+    /// How many paths ended some other way, by reason.
+    pub ended: BTreeMap<String, usize>,
+    pub steps: usize,
+}
+
+/// How a step left a path.
+enum Outcome {
+    Continue,
+    /// The path split at a branch on a symbol.
+    Fork(Box<Path>),
+    Ended(String),
+}
+
+struct Executor<'a> {
+    memory: &'a [u16],
+    /// Whether `In` reads symbolic characters, rather than ending the path.
+    symbolic_input: bool,
+}
+
+impl<'a> Executor<'a> {
+    fn word(&self, path: &Path, address: usize) -> Rc<Expr> {
+        match path.memory.get(&address) {
+            Some(expr) => expr.clone(),
+            None => Rc::new(Expr::Const(self.memory.get(address).copied().unwrap_or(0))),
+        }
+    }
+
+    /// The value of an operand: a literal or a register's value.
+    fn operand(path: &Path, value: Operand) -> Result<Rc<Expr>, String> {
+        match value {
+            Operand::Literal(literal) => Ok(Rc::new(Expr::Const(literal))),
+            Operand::Register(r) => Ok(path.registers[r].clone()),
+            Operand::Invalid(_) => Err(format!("invalid operand at {}", path.ip)),
+        }
+    }
+
+    fn concrete(expr: &Rc<Expr>, what: &str, ip: usize) -> Result<usize, String> {
+        expr.constant().map(|c| c as usize).ok_or_else(|| format!("symbolic {} at {}", what, ip))
+    }
+
+    fn step(&self, path: &mut Path) -> Result<Outcome, String> {
+        let symbolic = || format!("symbolic code at {}", path.ip);
+        let opcode = Opcode::from(self.word(path, path.ip).constant().ok_or_else(symbolic)?);
+        if opcode == Opcode::Unknown {
+            return Ok(Outcome::Ended(format!("unknown opcode at {}", path.ip)));
+        }
+
+        let operands = (1..=opcode.arg_count())
+            .map(|i| self.word(path, path.ip + i).constant().map(Operand::from_word).ok_or_else(symbolic))
+            .collect::<Result<Vec<_>, _>>()?;
+        let next = path.ip + 1 + operands.len();
+        let effect = ir::lift(opcode, &operands).ok_or_else(|| format!("destination isn't a register at {}", path.ip))?;
+
+        match effect {
+            Effect::Halt => return Ok(Outcome::Ended("halt".to_owned())),
+            Effect::Assign(r, expr) => {
+                path.registers[r] = match expr {
+                    ir::Expr::Copy(a) => Self::operand(path, a)?,
+                    ir::Expr::Binary(opcode, a, b) => Expr::binary(opcode, Self::operand(path, a)?, Self::operand(path, b)?),
+                    ir::Expr::Not(a) => Expr::not(Self::operand(path, a)?),
+                };
+            }
+            Effect::Push(value) => {
+                let value = Self::operand(path, value)?;
+                path.stack.push(value);
+            }
+            Effect::Pop(r) => {
+                path.registers[r] = path.stack.pop().ok_or("pop from an empty stack")?;
+            }
+            Effect::Load(r, from) => {
+                let address = Self::concrete(&Self::operand(path, from)?, "read address", path.ip)?;
+                path.registers[r] = self.word(path, address);
+            }
+            Effect::Store(to, value) => {
+                let address = Self::concrete(&Self::operand(path, to)?, "write address", path.ip)?;
+                let value = Self::operand(path, value)?;
+                path.memory.insert(address, value);
+            }
+            Effect::Jump(target) => {
+                path.ip = Self::concrete(&Self::operand(path, target)?, "jump target", path.ip)?;
+                return Ok(Outcome::Continue);
+            }
+            Effect::Branch { condition, nonzero, target } => {
+                let condition = Self::operand(path, condition)?;
+                let target = Self::concrete(&Self::operand(path, target)?, "jump target", path.ip)?;
+
+                match condition.constant() {
+                    Some(c) => path.ip = if (c != 0) == nonzero { target } else { next },
+                    None => {
+                        let mut jumped = path.clone();
+                        jumped.ip = target;
+                        let constraint = |holds| Constraint {
+                            expr: condition.clone(),
+                            holds,
+                        };
+                        jumped.constrain(constraint(nonzero));
+                        path.constrain(constraint(!nonzero));
+                        path.ip = next;
+
+                        return Ok(Outcome::Fork(Box::new(jumped)));
+                    }
+                }
+                return Ok(Outcome::Continue);
+            }
+            Effect::Call(target) => {
+                let target = Self::concrete(&Self::operand(path, target)?, "call target", path.ip)?;
+                path.stack.push(Rc::new(Expr::Const(next as u16)));
+                path.ip = target;
+                return Ok(Outcome::Continue);
+            }
+            Effect::Ret => {
+                let target = match path.stack.pop() {
+                    Some(target) => Self::concrete(&target, "return address", path.ip)?,
+                    None => return Ok(Outcome::Ended("return from an empty stack".to_owned())),
+                };
+                path.ip = target;
+                return Ok(Outcome::Continue);
+            }
+            Effect::Out(value) => {
+                let c = Self::operand(path, value)?.constant().map(|c| c as u8 as char).unwrap_or('?');
+                path.output.push(c);
+            }
+            Effect::In(r) => {
+                if !self.symbolic_input {
+                    return Ok(Outcome::Ended("input".to_owned()));
+                }
+                if path.input == MAX_INPUT {
+                    return Ok(Outcome::Ended("too much input".to_owned()));
+                }
+                path.registers[r] = Rc::new(Expr::Symbol(Symbol::Input(path.input)));
+                path.input += 1;
+            }
+            Effect::Noop => {}
+        }
+
+        path.ip = next;
+        Ok(Outcome::Continue)
+    }
+}
+
+/// Limits on how far `explore` goes.
+pub struct Limits {
+    /// Steps over all paths.
+    pub steps: usize,
+    /// Paths reaching the target.
+    pub reached: usize,
+}
+
+/// Explores every path from `start` with `symbols` left unknown, and `In` reading unknown
+/// characters if `symbolic_input` is set, looking for paths that reach `target`.
+pub fn explore(start: &Start, symbols: &[Symbol], symbolic_input: bool, target: usize, limits: &Limits) -> Report {
+    let executor = Executor {
+        memory: start.memory,
+        symbolic_input,
+    };
+
+    let mut initial = Path {
+        ip: start.ip,
+        registers: start.registers.map(|r| Rc::new(Expr::Const(r))),
+        stack: start.stack.iter().map(|&v| Rc::new(Expr::Const(v))).collect(),
+        memory: HashMap::new(),
+        constraints: Vec::new(),
+        input: 0,
+        output: String::new(),
+        domains: Domains::new(),
+        model: HashMap::new(),
+    };
+    for symbol in symbols {
+        let expr = Rc::new(Expr::Symbol(*symbol));
+        match *symbol {
+            Symbol::Register(r) => initial.registers[r] = expr,
+            Symbol::Memory(address) => {
+                initial.memory.insert(address, expr);
+            }
+            Symbol::Input(_) => {}
+        }
+    }
+
+    let mut report = Report {
+        reached: Vec::new(),
+        ended: BTreeMap::new(),
+        steps: 0,
+    };
+    let mut pending = vec![initial];
+
+    'paths: while let Some(mut path) = pending.pop() {
+        loop {
+            if path.ip == target {
+                report.reached.push(Reached {
+                    constraints: path.constraints,
+                    domains: path.domains,
+                    output: path.output,
+                });
+                if report.reached.len() == limits.reached {
+                    break 'paths;
+                }
+                continue 'paths;
+            }
+
+            if report.steps == limits.steps {
+                pending.push(path);
+                break 'paths;
+            }
+            report.steps += 1;
+
+            let ended = match executor.step(&mut path) {
+                Ok(Outcome::Continue) => continue,
+                Ok(Outcome::Fork(mut jumped)) => {
+                    // Only follow sides of the branch that can actually be taken
+                    if is_feasible(&mut jumped) {
+                        pending.push(*jumped);
+                    } else {
+                        *report.ended.entry("infeasible".to_owned()).or_insert(0) += 1;
+                    }
+                    if is_feasible(&mut path) {
+                        continue;
+                    }
+                    "infeasible".to_owned()
+                }
+                Ok(Outcome::Ended(reason)) | Err(reason) => reason,
+            };
+
+            *report.ended.entry(ended).or_insert(0) += 1;
+            continue 'paths;
+        }
+    }
+
+    if !pending.is_empty() {
+        report.ended.insert("unfinished (out of steps)".to_owned(), pending.len());
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    /// The paths from the start of `source` to its `found` label, with `symbols` unknown.
+    fn reach(source: &str, symbols: &[Symbol]) -> Report {
+        let memory = asm::assemble(source).ok().unwrap();
+        let target = memory.len() - 1;
+        let start = Start {
+            memory: &memory,
+            ip: 0,
+            registers: [0; 8],
+            stack: &[],
+        };
+        let limits = Limits { steps: 1000, reached: 10 };
+        explore(&start, symbols, false, target, &limits)
+    }
+
+    #[test]
+    fn solves_a_branch() {
+        let report = reach("eq r1 r0 5\njt r1 found\nhalt\nfound: halt", &[Symbol::Register(0)]);
+        assert_eq!(report.reached.len(), 1);
+
+        let path = &report.reached[0];
+        let solutions = solve(&path.constraints, &path.domains, 1_000_000, 32768);
+        assert_eq!(solutions.symbols, vec![Symbol::Register(0)]);
+        assert_eq!(solutions.values, vec![vec![5]]);
+        assert!(solutions.complete);
+    }
+
+    #[test]
+    fn gives_up_when_out_of_budget() {
+        let symbols = [Symbol::Register(0), Symbol::Register(1)];
+        let report = reach("mult r2 r0 r1\neq r3 r2 7\njt r3 found\nhalt\nfound: halt", &symbols);
+        assert_eq!(report.reached.len(), 1);
+
+        // Every value of r1 fails with r0 = 0, so a small budget runs out before any solution
+        let path = &report.reached[0];
+        let solutions = solve(&path.constraints, &path.domains, 1000, 32768);
+        assert!(solutions.values.is_empty());
+        assert!(!solutions.complete);
+    }
+}
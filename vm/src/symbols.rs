@@ -0,0 +1,50 @@
+//! Hand-written knowledge about challenge.bin, shared by the debugger and the static tools.
+
+use std::collections::HashMap;
+
+/// Decodes one obfuscated character: r0 is the encoded word, r1 the key.
+pub const DECODER: usize = 2125;
+
+/// Functions that call a routine on each character of a string:
+/// `(address, string register, routine register)`.
+pub const MAPPERS: [(usize, usize, usize); 2] = [(1458, 0, 1), (1543, 0, 1)];
+
+/// Routines for the mappers that print each character: `(address, key register)`, where
+/// the character is first decoded with the key in that register if there is one.
+pub const PRINTERS: [(usize, Option<usize>); 2] = [(1528, None), (1531, Some(2))];
+
+/// Functions that print a plain string: `(address, string register)`.
+pub const PRINT_FNS: [(usize, usize); 1] = [(1518, 0)];
+
+pub fn labels() -> HashMap<usize, String> {
+    let labels = vec![
+        (1458, "Map fn impl. r0: string memloc, r1: mapping routine. ret: r1 = 0 if early-exit (routine returns 32767)"),
+        (1518, "Print fn. r0: string memloc"),
+        (1528, "Print char. r0: char"),
+        (1531, "Print coded char. r0: encoded char, r2: key"),
+        (1543, "Map fn. r0: string memloc, r1: mapping routine. ret: r0 = 32767 on succ, r0 = r2 otherwise"),
+        (1605, "Char matcher fn. r0: incoming char, r2: needle. ret: r1 = 23767 if a match is found."),
+        (1605, "Char matcher impl. r0: incoming char, r2: needle. ret: r1 = 23767 if a match is found."),
+        (2125, "Decoder logic. r0: encoded char, r1: key. ret: r0 = decoded char."),
+        (2826, "Main loop (?)")
+    ];
+
+    labels
+        .into_iter()
+        .map(|(k, v)| (k as usize, v.to_owned()))
+        .collect()
+}
+
+/// The part of a label before its first sentence, which reads as a function name.
+pub fn short_label(label: &str) -> &str {
+    label.split('.').next().unwrap_or(label)
+}
+
+/// A function's name: the short form of its label, or `sub_<address>` without one.
+pub fn function_name(address: usize, labels: &HashMap<usize, String>) -> String {
+    match labels.get(&address) {
+        Some(label) => short_label(label).to_owned(),
+        None if address == 0 => "start".to_owned(),
+        None => format!("sub_{}", address),
+    }
+}
@@ -0,0 +1,256 @@
+//! Taint tracking: a shadow of the registers, stack and memory recording which input
+//! characters each value was computed from, and which memory address it was loaded from,
+//! so printed text can be traced back to the code and data behind it.
+
+use super::disasm;
+use super::ir::{Effect, Expr};
+use super::opcode::{Opcode, Operand};
+use super::strings;
+use super::util;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Where a value came from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Taint {
+    /// The input characters it depends on, by position in everything `In` has read.
+    pub inputs: BTreeSet<usize>,
+    /// The memory address it was loaded from, if any. A value computed from two loaded
+    /// values keeps the first one's address.
+    pub source: Option<u16>,
+}
+
+impl Taint {
+    fn join(&self, other: &Taint) -> Taint {
+        Taint {
+            inputs: self.inputs.union(&other.inputs).copied().collect(),
+            source: self.source.or(other.source),
+        }
+    }
+
+    /// Whether the value doesn't depend on input, wherever it was loaded from.
+    pub fn is_clean(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    /// The positions along with the characters at them, e.g. `in[0..=3] "look"`.
+    pub fn describe(&self, text: &[char]) -> String {
+        let chars: String = self.inputs.iter().filter_map(|&i| text.get(i)).collect();
+        format!("in[{}] {:?}", util::ranges(self.inputs.iter().copied()), chars)
+    }
+}
+
+/// A character printed by `Out`.
+pub struct Emitted {
+    pub c: char,
+    /// The address of the `Out`.
+    pub ip: usize,
+    /// The call sites leading to it, outermost first.
+    pub call_stack: Vec<u16>,
+    pub source: Option<u16>,
+}
+
+/// An instruction that was seen to act on input.
+pub struct Site {
+    /// `compare` for `Eq` and `Gt`, `branch` for `JmpIfTrue` and `JmpIfFalse`.
+    pub kind: &'static str,
+    pub taint: Taint,
+    pub count: usize,
+}
+
+pub struct Shadow {
+    registers: [Taint; 8],
+    stack: Vec<Taint>,
+    /// Only words that are tainted.
+    memory: HashMap<u16, Taint>,
+    /// Every character read so far, indexed by position.
+    pub text: Vec<char>,
+    /// Comparisons and branches on tainted values, by address.
+    pub sites: BTreeMap<usize, Site>,
+    /// Everything printed so far.
+    pub output: Vec<Emitted>,
+}
+
+impl Default for Shadow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shadow {
+    pub fn new() -> Shadow {
+        Shadow {
+            registers: Default::default(),
+            stack: Vec::new(),
+            memory: HashMap::new(),
+            text: Vec::new(),
+            sites: BTreeMap::new(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn register(&self, r: usize) -> &Taint {
+        &self.registers[r]
+    }
+
+    pub fn memory(&self, address: u16) -> Option<&Taint> {
+        self.memory.get(&address)
+    }
+
+    /// How many memory words depend on input.
+    pub fn tainted_words(&self) -> usize {
+        self.memory.values().filter(|t| !t.is_clean()).count()
+    }
+
+    fn operand(&self, value: Operand) -> Taint {
+        match value {
+            Operand::Register(r) => self.registers[r].clone(),
+            _ => Taint::default(),
+        }
+    }
+
+    fn record(&mut self, address: usize, kind: &'static str, taint: Taint) {
+        if taint.is_clean() {
+            return;
+        }
+
+        let site = self.sites.entry(address).or_insert(Site {
+            kind,
+            taint: Taint::default(),
+            count: 0,
+        });
+        site.taint = site.taint.join(&taint);
+        site.count += 1;
+    }
+
+    /// Carries taint through `effect`, found at `address`, just before it's executed with
+    /// `registers`. `In` is handled by `read` instead, once the character is known.
+    ///
+    /// A value read from memory carries the taint of the word it's read from but not of
+    /// the address, so looking something up by an input character doesn't taint the result.
+    pub fn step(&mut self, address: usize, effect: &Effect, registers: &[u16]) {
+        let resolve = |value: Operand| match value {
+            Operand::Register(r) => registers[r],
+            Operand::Literal(c) | Operand::Invalid(c) => c,
+        };
+
+        match *effect {
+            Effect::Assign(r, Expr::Binary(Opcode::Eq | Opcode::Gt, a, b)) => {
+                let taint = self.operand(a).join(&self.operand(b));
+                self.record(address, "compare", taint.clone());
+                // A truth value isn't the data it was computed from
+                self.registers[r] = Taint { source: None, ..taint };
+            }
+            Effect::Assign(r, expr) => {
+                let taint = expr.operands().iter().fold(Taint::default(), |t, &v| t.join(&self.operand(v)));
+                self.registers[r] = taint;
+            }
+            Effect::Branch { condition, .. } => {
+                let taint = self.operand(condition);
+                self.record(address, "branch", taint);
+            }
+            Effect::Push(value) => {
+                let taint = self.operand(value);
+                self.stack.push(taint);
+            }
+            Effect::Pop(r) => {
+                self.registers[r] = self.stack.pop().unwrap_or_default();
+            }
+            Effect::Load(r, from) => {
+                // A copied value keeps the address of the original
+                let from = resolve(from);
+                let mut taint = self.memory.get(&from).cloned().unwrap_or_default();
+                taint.source = taint.source.or(Some(from));
+                self.registers[r] = taint;
+            }
+            Effect::Store(to, value) => {
+                let (to, taint) = (resolve(to), self.operand(value));
+                if taint == Taint::default() {
+                    self.memory.remove(&to);
+                } else {
+                    self.memory.insert(to, taint);
+                }
+            }
+            Effect::Call(_) => self.stack.push(Taint::default()),
+            Effect::Ret => {
+                self.stack.pop();
+            }
+            _ => {}
+        }
+    }
+
+    /// Taints register `r` with the position of the character `In` just read into it.
+    pub fn read(&mut self, r: usize, c: u16) {
+        let taint = Taint {
+            inputs: BTreeSet::from([self.text.len()]),
+            source: None,
+        };
+        self.text.push(c as u8 as char);
+        self.registers[r] = taint;
+    }
+
+    /// One line per comparison or branch on input, with its disassembly and the
+    /// characters involved.
+    pub fn report(&self, memory: &[u16], labels: &HashMap<usize, String>) -> Vec<String> {
+        self.sites
+            .iter()
+            .map(|(&address, site)| {
+                let operation = disasm::decode(memory, address).map(|d| d.operation()).unwrap_or_default();
+                format!(
+                    "{:<32} {:<24} ; {} x{} on {}",
+                    util::describe_address(address, labels),
+                    operation,
+                    site.kind,
+                    site.count,
+                    site.taint.describe(&self.text)
+                )
+            })
+            .collect()
+    }
+
+    /// Records the character `Out` at `ip` just printed from `operand`.
+    pub fn emit(&mut self, ip: usize, call_stack: &[u16], operand: Operand, c: u16) {
+        self.output.push(Emitted {
+            c: c as u8 as char,
+            ip,
+            call_stack: call_stack.to_vec(),
+            source: self.operand(operand).source,
+        });
+    }
+
+    /// Where the most recent printing of `text` came from: one paragraph per run of
+    /// characters printed by the same `Out` from the same call stack, giving the call stack
+    /// and the memory the characters were loaded from.
+    pub fn whence(&self, text: &str, table: &strings::Table, labels: &HashMap<usize, String>) -> Option<Vec<String>> {
+        let printed: Vec<char> = self.output.iter().map(|e| e.c).collect();
+        let wanted: Vec<char> = text.chars().collect();
+        let start = (0..=printed.len().checked_sub(wanted.len())?)
+            .rev()
+            .find(|&i| printed[i..i + wanted.len()] == wanted[..])?;
+
+        let emitted = &self.output[start..start + wanted.len()];
+        let mut lines = Vec::new();
+
+        for run in emitted.chunk_by(|a, b| a.ip == b.ip && a.call_stack == b.call_stack) {
+            let chars: String = run.iter().map(|e| e.c).collect();
+            lines.push(format!("{:?} printed at {}", chars, util::describe_address(run[0].ip, labels)));
+
+            for &site in run[0].call_stack.iter().rev() {
+                lines.push(format!("    called from {}", util::describe_address(site as usize, labels)));
+            }
+
+            let sources: BTreeSet<usize> = run.iter().filter_map(|e| e.source).map(|s| s as usize).collect();
+            if sources.is_empty() {
+                lines.push("    not loaded from memory".to_owned());
+                continue;
+            }
+            lines.push(format!("    loaded from {}", util::ranges(sources.iter().copied())));
+
+            let first = *sources.iter().next().unwrap();
+            if let Some(found) = table.containing(first) {
+                lines.push(format!("    in the string at {} {}", found.address, found.describe()));
+            }
+        }
+
+        Some(lines)
+    }
+}
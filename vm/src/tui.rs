@@ -0,0 +1,228 @@
+use super::debug::Debugger;
+use super::exec::State;
+use super::util;
+use std::process::Command;
+use std::sync::Mutex;
+
+pub const RESET: &str = "\x1b[2J\x1b[H";
+
+// Rows reserved for the memory pane and the output panes, borders included.
+const MEMORY_HEIGHT: usize = 10;
+const OUTPUT_HEIGHT: usize = 12;
+const REGISTERS_HEIGHT: usize = 10;
+
+// A border above and below a single line.
+const MIN_PANE_HEIGHT: usize = 3;
+
+// The command line, the fixed panes, and the stack and call stack panes below the registers.
+const MIN_HEIGHT: usize = 1 + MEMORY_HEIGHT + OUTPUT_HEIGHT + REGISTERS_HEIGHT + 2 * MIN_PANE_HEIGHT;
+const MIN_WIDTH: usize = 60;
+
+/// How many lines of game output the output pane shows.
+pub const SCROLLBACK: usize = OUTPUT_HEIGHT - 2;
+
+// The terminal's size, asked for once rather than on every redraw.
+static SIZE: Mutex<Option<(usize, usize)>> = Mutex::new(None);
+
+struct Pane {
+    title: String,
+    lines: Vec<String>,
+}
+
+impl Pane {
+    fn new(title: &str, lines: Vec<String>) -> Pane {
+        Pane {
+            title: title.to_owned(),
+            lines,
+        }
+    }
+
+    /// Draws the pane inside a box exactly `width` x `height` characters big.
+    fn frame(&self, width: usize, height: usize) -> Vec<String> {
+        let inner = width.saturating_sub(2);
+        let mut framed = Vec::with_capacity(height);
+
+        let title = format!(" {} ", self.title);
+        let title: String = title.chars().take(inner).collect();
+        framed.push(format!("+{}{}+", title, "-".repeat(inner - title.len())));
+
+        for i in 0..height.saturating_sub(2) {
+            let line = self.lines.get(i).map(String::as_str).unwrap_or("");
+            let line: String = line.chars().take(inner).collect();
+            framed.push(format!("|{:width$}|", line, width = inner));
+        }
+
+        framed.push(format!("+{}+", "-".repeat(inner)));
+        framed
+    }
+}
+
+fn beside(left: Vec<String>, right: Vec<String>) -> Vec<String> {
+    left.into_iter().zip(right).map(|(l, r)| l + &r).collect()
+}
+
+/// Asks for the terminal's size again on the next redraw, e.g. when the UI is turned back on
+/// after the window may have been resized.
+pub fn forget_size() {
+    *SIZE.lock().unwrap() = None;
+}
+
+fn terminal_size() -> (usize, usize) {
+    *SIZE.lock().unwrap().get_or_insert_with(query_size)
+}
+
+/// The terminal's `(columns, rows)` as `stty` reports them, falling back on `COLUMNS` and
+/// `LINES` when there's no terminal to ask.
+fn query_size() -> (usize, usize) {
+    let read = |name: &str, default: usize| {
+        std::env::var(name)
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(default)
+    };
+
+    let queried = std::fs::File::open("/dev/tty").ok().and_then(|tty| {
+        let output = Command::new("stty").arg("size").stdin(tty).output().ok()?;
+        let size = String::from_utf8(output.stdout).ok()?;
+        let (rows, columns) = size.trim().split_once(' ')?;
+        Some((columns.parse().ok()?, rows.parse().ok()?))
+    });
+
+    queried.unwrap_or_else(|| (read("COLUMNS", 120), read("LINES", 45)))
+}
+
+/// Appends the disassembly of `address` and returns the address of the following instruction.
+fn disassemble_at(debugger: &Debugger, state: &State, address: usize, lines: &mut Vec<String>) -> usize {
+    let marker = if address == state.ip {
+        "=>"
+    } else if debugger.breakpoints.contains(&(address as u16)) {
+        " *"
+    } else {
+        "  "
+    };
+
+    match state.instruction(address) {
+        Some(instruction) => {
+            for line in instruction.disassemble(address, &debugger.labels, &state.registers).lines() {
+                lines.push(if line.contains(" / ") {
+                    format!("{} {:5}: {}", marker, address, line)
+                } else {
+                    format!("   ; {}", line)
+                });
+            }
+
+            address + instruction.size()
+        }
+        None => {
+            let word = state.instructions.get(&(address as u16));
+            let word = word.map(|w| w.to_string()).unwrap_or_else(|| "<BLANK>".to_owned());
+            lines.push(format!("{} {:5}: ?? {}", marker, address, word));
+
+            address + 1
+        }
+    }
+}
+
+fn disassembly(debugger: &Debugger, state: &State, height: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let current = state.ip;
+
+    for &address in debugger.recent.iter().filter(|&&ip| ip != current) {
+        disassemble_at(debugger, state, address, &mut lines);
+    }
+
+    let mut ip = current;
+    while lines.len() < height {
+        ip = disassemble_at(debugger, state, ip, &mut lines);
+    }
+
+    lines
+}
+
+fn registers(state: &State) -> Vec<String> {
+    state
+        .registers
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            let line = format!("r{}: {:5}  {:#06x}  {}", i, v, v, util::maybe_to_ascii(*v));
+            match state.shadow.as_ref().map(|s| s.register(i)).filter(|t| !t.is_clean()) {
+                Some(taint) => format!("{}  {}", line, taint.describe(&state.shadow.as_ref().unwrap().text)),
+                None => line,
+            }
+        })
+        .collect()
+}
+
+fn stack(state: &State) -> Vec<String> {
+    state
+        .stack
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, v)| format!("{:3}: {:5}", i, v))
+        .collect()
+}
+
+fn call_stack(debugger: &Debugger, state: &State) -> Vec<String> {
+    state
+        .call_stack
+        .iter()
+        .rev()
+        .map(|&site| {
+            let target = state.instructions.get(&(site + 1)).copied().unwrap_or(0);
+            match debugger.labels.get(&(target as usize)) {
+                Some(label) => format!("{:5} -> {} ({})", site, target, label),
+                None => format!("{:5} -> {}", site, util::register_pretty(&target)),
+            }
+        })
+        .collect()
+}
+
+fn tail(text: &str, count: usize) -> Vec<String> {
+    let lines: Vec<_> = text.lines().collect();
+    let skip = lines.len().saturating_sub(count);
+    lines[skip..].iter().map(|l| l.to_string()).collect()
+}
+
+/// Redraws the whole debugger screen on stderr, leaving the cursor on the last line
+/// so the regular prompt lands in the command line.
+/// A terminal smaller than the minimum gets the top-left corner of the screen.
+pub fn render(debugger: &Debugger, state: &State, output: &str) {
+    let (terminal_width, terminal_height) = terminal_size();
+    let (width, height) = (terminal_width.max(MIN_WIDTH), terminal_height.max(MIN_HEIGHT));
+    let top_height = height - 1 - MEMORY_HEIGHT - OUTPUT_HEIGHT;
+    let left_width = width * 3 / 5;
+    let right_width = width - left_width;
+
+    let disassembly = Pane::new("Disassembly", disassembly(debugger, state, top_height - 2));
+    let stack_height = (top_height - REGISTERS_HEIGHT) / 2;
+    let mut right = Pane::new("Registers", registers(state)).frame(right_width, REGISTERS_HEIGHT);
+    right.extend(Pane::new("Stack", stack(state)).frame(right_width, stack_height));
+    right.extend(
+        Pane::new("Call Stack", call_stack(debugger, state))
+            .frame(right_width, top_height - REGISTERS_HEIGHT - stack_height),
+    );
+
+    let mut screen = beside(disassembly.frame(left_width, top_height), right);
+
+    // Escape codes would throw off the pane widths
+    let mut hexview = debugger.hexview.clone();
+    hexview.color = false;
+
+    let rows = MEMORY_HEIGHT - 2;
+    let memory = hexview.render(&state.memory(), state.ip, rows * hexview.columns, &debugger.labels);
+    screen.extend(Pane::new("Memory", memory).frame(width, MEMORY_HEIGHT));
+
+    let game = Pane::new("Output", tail(&state.output, SCROLLBACK));
+    let command = Pane::new("Debugger", tail(output, OUTPUT_HEIGHT - 2));
+    screen.extend(beside(
+        game.frame(left_width, OUTPUT_HEIGHT),
+        command.frame(right_width, OUTPUT_HEIGHT),
+    ));
+
+    // Leave the last row for the prompt
+    screen.resize(terminal_height.saturating_sub(1), String::new());
+    let screen: Vec<String> = screen.iter().map(|line| line.chars().take(terminal_width).collect()).collect();
+    eprintln!("{}{}", RESET, screen.join("\n"));
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 pub fn to_ascii(n: u16) -> Option<char> {
     if n < 128 {
         Some(n as u8 as char)
@@ -14,16 +16,18 @@ pub fn maybe_to_ascii(n: u16) -> char {
     }
 }
 
-pub fn maybe_to_ascii_coded(n: u16, key: u16) -> char {
+pub fn decode(n: u16, key: u16) -> u16 {
   let r0 = n;
   let r1 = key;
 
   let r2 = r0 & r1;
   let r2 = !r2;
   let r0 = r0 | r1;
-  let r0 = r0 & r2;
+  r0 & r2
+}
 
-  return maybe_to_ascii(r0);
+pub fn maybe_to_ascii_coded(n: u16, key: u16) -> char {
+  maybe_to_ascii(decode(n, key))
 }
 
 pub fn register_pretty(s: &u16) -> String {
@@ -39,3 +43,77 @@ pub fn register_pretty(s: &u16) -> String {
         _ => s.to_string(),
     }
 }
+
+// How far past a label an address can be and still be described relative to it. Further away,
+// the label most likely belongs to some unrelated function or data.
+const MAX_LABEL_OFFSET: usize = 256;
+
+/// An address along with the closest label at or before it, e.g. `1460 (Map fn impl+2)`.
+/// Addresses more than `MAX_LABEL_OFFSET` words past the label are left bare.
+pub fn describe_address(address: usize, labels: &HashMap<usize, String>) -> String {
+    let label = labels
+        .iter()
+        .filter(|(&at, _)| at <= address && address - at <= MAX_LABEL_OFFSET)
+        .max_by_key(|(&at, _)| at);
+
+    match label {
+        Some((&at, label)) if at == address => format!("{} ({})", address, label),
+        Some((&at, label)) => format!("{} ({}+{})", address, label, address - at),
+        None => address.to_string(),
+    }
+}
+
+/// Sorted values as ranges, e.g. `1..=5, 7`.
+pub fn ranges(values: impl IntoIterator<Item = usize>) -> String {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for v in values {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == v => *end = v,
+            _ => ranges.push((v, v)),
+        }
+    }
+
+    ranges
+        .iter()
+        .map(|&(a, b)| if a == b { a.to_string() } else { format!("{}..={}", a, b) })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A small xorshift generator, for when something random but repeatable is needed.
+#[cfg(test)]
+pub struct Rng(u64);
+
+#[cfg(test)]
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed.max(1))
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number in `0..n`.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_addresses_near_a_label() {
+        let labels = HashMap::from([(100, "fn".to_owned())]);
+
+        assert_eq!(describe_address(100, &labels), "100 (fn)");
+        assert_eq!(describe_address(102, &labels), "102 (fn+2)");
+        assert_eq!(describe_address(99, &labels), "99");
+        assert_eq!(describe_address(100 + MAX_LABEL_OFFSET + 1, &labels), "357");
+    }
+}
@@ -0,0 +1,107 @@
+//! Static checks on every reachable instruction, for images that didn't come from the
+//! challenge and may not be well formed.
+
+use super::disasm::Analysis;
+use super::opcode::{Opcode, Operand, Role};
+
+pub struct Problem {
+    pub address: usize,
+    /// The instruction, or its raw words if it can't be decoded.
+    pub text: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:5}: {:<24} ; {}", self.address, self.text, self.message)
+    }
+}
+
+fn raw(memory: &[u16], address: usize) -> String {
+    let end = (address + 4).min(memory.len());
+    let words: Vec<_> = memory[address..end].iter().map(|w| w.to_string()).collect();
+    format!(".data {}", words.join(", "))
+}
+
+/// Checks the instructions `analysis` reached in `memory`: valid opcodes and operands,
+/// registers where a result is written, literal jump targets inside the image, and no
+/// instruction running off its end.
+pub fn verify(memory: &[u16], analysis: &Analysis) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    // Addresses past the end are reported at the instruction that gets there
+    for &address in analysis.invalid.iter().filter(|&&a| a < memory.len()) {
+        let word = memory[address];
+        let text = raw(memory, address);
+        let message = match Opcode::from(word) {
+            Opcode::Unknown => format!("invalid opcode {}", word),
+            opcode => format!("{} needs {} operand(s) but the image ends first", opcode, opcode.arg_count()),
+        };
+        problems.push(Problem { address, text, message });
+    }
+
+    for instruction in analysis.code.values() {
+        let mut messages = Vec::new();
+
+        for (i, operand) in instruction.operands.iter().enumerate() {
+            if let Operand::Invalid(word) = operand {
+                messages.push(format!("operand {} is {}, past the last register", i + 1, word));
+            }
+        }
+
+        let roles = instruction.operands.iter().zip(instruction.opcode.roles());
+        for (operand, _) in roles.filter(|&(o, &role)| role == Role::Destination && o.register().is_none()) {
+            messages.push(format!("writes to {}, which isn't a register", operand.word()));
+        }
+
+        if let Some(target) = instruction.target().filter(|&t| t >= memory.len()) {
+            messages.push(format!("target {} is past the end of the image ({} words)", target, memory.len()));
+        }
+        if instruction.falls_through() && instruction.next() >= memory.len() {
+            messages.push("runs off the end of the image".to_owned());
+        }
+
+        let text = instruction.operation();
+        problems.extend(messages.into_iter().map(|message| Problem {
+            address: instruction.address,
+            text: text.clone(),
+            message,
+        }));
+    }
+
+    problems.sort_by_key(|p| p.address);
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, disasm};
+
+    fn problems(source: &str) -> Vec<(usize, String)> {
+        let memory = asm::assemble(source).ok().unwrap();
+        let analysis = disasm::recursive_descent(&memory, &[0]);
+        verify(&memory, &analysis).into_iter().map(|p| (p.address, p.message)).collect()
+    }
+
+    #[test]
+    fn accepts_a_well_formed_image() {
+        assert!(problems("loop: add r0 r0 1\njt r0 loop\nhalt").is_empty());
+    }
+
+    #[test]
+    fn reports_bad_operands_and_targets() {
+        // out 40000; set 5 1; jmp 100
+        let source = ".data 19, 40000\n.data 1, 5, 1\n.data 6, 100";
+        assert_eq!(
+            problems(source),
+            vec![
+                (0, "operand 1 is 40000, past the last register".to_owned()),
+                (2, "writes to 5, which isn't a register".to_owned()),
+                (5, "target 100 is past the end of the image (7 words)".to_owned()),
+            ]
+        );
+        assert_eq!(problems("noop"), vec![(0, "runs off the end of the image".to_owned())]);
+        assert_eq!(problems(".data 42"), vec![(0, "invalid opcode 42".to_owned())]);
+    }
+}
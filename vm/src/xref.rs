@@ -0,0 +1,121 @@
+//! Cross-references: who calls, jumps to, reads or writes each address.
+
+use super::disasm::Analysis;
+use super::ir::Effect;
+use super::opcode::{Opcode, Operand};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    Call,
+    Jump,
+    /// An `RMem` with a literal address.
+    Read,
+    /// A `WMem` with a literal address.
+    Write,
+}
+
+impl std::fmt::Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Kind::Call => "call",
+            Kind::Jump => "jmp",
+            Kind::Read => "read",
+            Kind::Write => "write",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Xref {
+    pub kind: Kind,
+    /// Address of the referring instruction.
+    pub from: usize,
+}
+
+pub struct Xrefs {
+    refs: BTreeMap<usize, Vec<Xref>>,
+}
+
+impl Xrefs {
+    /// Indexes every instruction that `analysis` found to be code.
+    pub fn build(analysis: &Analysis) -> Xrefs {
+        let mut xrefs = Xrefs {
+            refs: BTreeMap::new(),
+        };
+
+        for instruction in analysis.code.values() {
+            let reference = match instruction.lift() {
+                Some(Effect::Call(_)) => instruction.target().map(|t| (t, Kind::Call)),
+                Some(Effect::Jump(_) | Effect::Branch { .. }) => instruction.target().map(|t| (t, Kind::Jump)),
+                Some(Effect::Load(_, Operand::Literal(a))) => Some((a as usize, Kind::Read)),
+                Some(Effect::Store(Operand::Literal(a), _)) => Some((a as usize, Kind::Write)),
+                _ => None,
+            };
+
+            if let Some((to, kind)) = reference {
+                xrefs.add(to, kind, instruction.address);
+            }
+        }
+
+        xrefs
+    }
+
+    /// Adds a call or jump for each target of an indirect call or jump, by site.
+    pub fn add_indirect(&mut self, analysis: &Analysis, targets: &BTreeMap<usize, BTreeSet<usize>>) {
+        for (site, found) in targets {
+            let kind = match analysis.code.get(site).map(|i| i.opcode) {
+                Some(Opcode::Call) => Kind::Call,
+                Some(_) => Kind::Jump,
+                None => continue,
+            };
+
+            for &to in found {
+                self.add(to, kind, *site);
+            }
+        }
+    }
+
+    pub fn add(&mut self, to: usize, kind: Kind, from: usize) {
+        let refs = self.refs.entry(to).or_default();
+        let xref = Xref { kind, from };
+
+        if let Err(index) = refs.binary_search(&xref) {
+            refs.insert(index, xref);
+        }
+    }
+
+    /// Everything referring to `address`, calls first.
+    pub fn to(&self, address: usize) -> &[Xref] {
+        self.refs.get(&address).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    pub fn is_referenced(&self, address: usize) -> bool {
+        self.refs.contains_key(&address)
+    }
+
+    /// A one-line summary like `call 1234, 1300; jmp 1250`.
+    pub fn describe(&self, address: usize) -> Option<String> {
+        let refs = self.to(address);
+        if refs.is_empty() {
+            return None;
+        }
+
+        let mut groups: Vec<(Kind, Vec<String>)> = Vec::new();
+        for xref in refs {
+            match groups.last_mut() {
+                Some((kind, froms)) if *kind == xref.kind => froms.push(xref.from.to_string()),
+                _ => groups.push((xref.kind, vec![xref.from.to_string()])),
+            }
+        }
+
+        let groups: Vec<_> = groups
+            .into_iter()
+            .map(|(kind, froms)| format!("{} {}", kind, froms.join(", ")))
+            .collect();
+
+        Some(groups.join("; "))
+    }
+}